@@ -1,14 +1,23 @@
 extern crate chrono;
 extern crate timer;
+use crate::machine::{self, Action, Guard, Input};
+use crate::persist::{RestorePolicy, RulesFile, SavedState, StateFile};
+use crate::profile::BrewProfile;
+use crate::recurring::WeeklyRule;
+use crate::scheduler::Scheduler;
 use chrono::DateTime;
 use chrono::Duration;
 use chrono::TimeZone;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use timer::Guard;
+use chrono::Utc;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
-#[derive(PartialEq, Copy, Clone, Debug)]
+#[derive(PartialEq, Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum PotState {
     Idle = 1,
@@ -16,148 +25,1610 @@ pub enum PotState {
     Waiting = 3,
     Active = 4,
     Shutdown = 5,
+    KeepWarm = 6,
+    Fault = 7,
 }
 
-struct CoffeepotInternals {
+impl PotState {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(PotState::Idle),
+            2 => Some(PotState::Ready),
+            3 => Some(PotState::Waiting),
+            4 => Some(PotState::Active),
+            5 => Some(PotState::Shutdown),
+            6 => Some(PotState::KeepWarm),
+            7 => Some(PotState::Fault),
+            _ => None,
+        }
+    }
+
+    /** Whether the coffeemaker should be receiving power */
+    pub fn powers_relay(self) -> bool {
+        matches!(self, PotState::Active | PotState::KeepWarm)
+    }
+}
+
+/** The output the brew itself runs on, the coffeemaker's relay */
+pub const BREWER: &str = "brewer";
+/** The stage of an Active brew after any stages of its profile */
+pub const BREW_STAGE: &str = "brew";
+
+/** When a brew starts, and for how long the relay stays on */
+#[derive(PartialEq, Clone, Debug)]
+pub struct Activation {
+    pub at: DateTime<Utc>,
+    pub brew_time: Duration,
+    /** The brew profile it was asked for with, if any */
+    pub profile: Option<String>,
+}
+
+/** How long to brew, as asked for by a command */
+#[derive(PartialEq, Clone, Debug)]
+pub enum Brew {
+    /** The default brew time */
+    Default,
+    For(Duration),
+    /** The brew time, and keep warm time if set, of a named profile */
+    Profile(String),
+}
+
+impl From<Duration> for Brew {
+    fn from(time: Duration) -> Self {
+        Brew::For(time)
+    }
+}
+
+/** An entry in the queue of delayed activations */
+#[derive(PartialEq, Clone, Debug)]
+pub struct ScheduledActivation {
+    pub id: u32,
+    pub activation: Activation,
+}
+
+/** Why a request was refused, leaving the state as it was */
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum CoffeepotError {
+    /** Delayed activations need the pot to be Ready or Waiting */
+    NotArmed,
+    InvalidInState(PotState),
+    /** Everything but clearing the fault is refused while faulted */
+    Faulted,
+    /** No scheduled activation or weekly rule with this id */
+    UnknownId(u32),
+    /** An activation can't be moved to or set for a time that has passed */
+    InPast,
+    /** Brews must be longer than zero and at most this long */
+    InvalidBrewTime(Duration),
+    UnknownProfile,
+    /** A time too far out to be represented */
+    OutOfRange,
+    ShutDown,
+}
+
+impl fmt::Display for CoffeepotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoffeepotError::NotArmed => write!(f, "not armed"),
+            CoffeepotError::InvalidInState(state) => write!(f, "invalid in state {:?}", state),
+            CoffeepotError::Faulted => write!(f, "faulted, the fault must be cleared first"),
+            CoffeepotError::UnknownId(id) => write!(f, "no entry with id {}", id),
+            CoffeepotError::InPast => write!(f, "activation time has passed"),
+            CoffeepotError::InvalidBrewTime(max) => {
+                write!(f, "brew time must be between 0 and {} minutes", max.num_minutes())
+            }
+            CoffeepotError::UnknownProfile => write!(f, "no such brew profile"),
+            CoffeepotError::OutOfRange => write!(f, "time out of range"),
+            CoffeepotError::ShutDown => write!(f, "shut down"),
+        }
+    }
+}
+
+impl Error for CoffeepotError {}
+
+/** What the pot is Waiting for */
+#[derive(Copy, Clone, Debug)]
+enum Due {
+    Scheduled(u32),
+    Rule(u32),
+}
+
+/** What the brew in progress is doing, see `CoffeepotInternals::step` */
+#[derive(PartialEq, Clone, Debug, Default)]
+struct Step {
+    stage: Option<String>,
+    outputs: Vec<String>,
+    /** When the next stage or pulse starts, unless this is the last one */
+    next: Option<DateTime<Utc>>,
+}
+
+/** What to do when the pending timer fires */
+#[derive(Copy, Clone, Debug)]
+enum TimerEvent {
+    FinishBrew,
+    /** The next stage or pulse of the brew */
+    NextStep,
+    KeepWarmOver,
+    ReadyExpired,
+    /** Along with when it was due */
+    Due(Due, DateTime<Utc>),
+}
+
+type Job<S> = Box<dyn FnOnce(&mut CoffeepotInternals<S>) + Send>;
+
+/** Everything the event loop acts on, in the order it was sent */
+enum Message<S: Scheduler> {
+    Call(Job<S>),
+    /**
+     * Tagged with the timer generation, so that cancelled timers are ignored,
+     * and answered once handled
+     */
+    Timer(u64, TimerEvent, Sender<()>),
+}
+
+struct CoffeepotInternals<S: Scheduler> {
     state: PotState,
-    timer_guard: Option<Guard>,
-    clock: timer::Timer,
-    tx: Sender<PotState>,
+    /** The current brew, only set while Active or keeping it warm */
+    activation: Option<Activation>,
+    /** Sorted by activation time, the first entry drives Waiting */
+    schedule: Vec<ScheduledActivation>,
+    next_id: u32,
+    rules: Vec<WeeklyRule>,
+    next_rule_id: u32,
+    /** Weekly rule occurrences that were cancelled or moved */
+    skipped: Vec<(u32, DateTime<Utc>)>,
+    /** The scheduled activation the timer is set for, kept until it fires */
+    due_entry: Option<u32>,
+    /** For commands that don't ask for a brew time or profile */
+    brew_time: Duration,
+    /** The longest brew that will be accepted */
+    max_brew_time: Duration,
+    profiles: Vec<BrewProfile>,
+    /** How long to stay in KeepWarm after a brew, zero skips it */
+    keep_warm_time: Duration,
+    /** When the pot was last armed, only set while Ready or Waiting */
+    armed_at: Option<DateTime<Utc>>,
+    /** How long the pot may sit armed, Ready or Waiting, before being disarmed */
+    ready_expiry: Option<Duration>,
+    timer_guard: Option<S::Guard>,
+    /** Bumped every time the timer is cancelled */
+    timer_generation: u64,
+    clock: S,
+    /** For timers to send their events back to the event loop */
+    tx: Sender<Message<S>>,
+    subscribers: Vec<Subscriber>,
+    next_subscriber_id: u32,
+    /** The stage and outputs subscribers were last told about */
+    stage: Option<String>,
+    outputs: Vec<String>,
+    store: Option<StateFile>,
+    rules_store: Option<RulesFile>,
 }
 
-impl CoffeepotInternals {
+impl<S: Scheduler> CoffeepotInternals<S> {
+    /**
+     * Owns the state machine until shutdown. Commands and timer events are
+     * handled one at a time, so nothing else ever touches the state.
+     */
+    fn run(mut self, rx: Receiver<Message<S>>) {
+        for message in rx {
+            match message {
+                Message::Call(job) => job(&mut self),
+                Message::Timer(generation, event, done) => {
+                    if generation == self.timer_generation {
+                        self.on_timer(event);
+                    } else {
+                        debug!("ignoring cancelled timer {:?}", event);
+                    }
+                    done.send(()).ok();
+                }
+            }
+            if self.state == PotState::Shutdown {
+                break;
+            }
+        }
+        info!("exiting coffeepot loop");
+    }
+
     fn cancel_timer(&mut self) {
-        match &self.timer_guard {
-            Some(guard) => {
-                debug!("cancelling coffeepot timer");
-                drop(guard);
-                self.timer_guard = None;
+        self.timer_generation += 1;
+        self.due_entry = None;
+        if self.timer_guard.take().is_some() {
+            debug!("cancelling coffeepot timer");
+        }
+    }
+
+    fn set_timer(&mut self, at: DateTime<Utc>, event: TimerEvent) {
+        self.cancel_timer();
+        let generation = self.timer_generation;
+        let tx = self.tx.clone();
+        // waits for the event to be handled, so that a virtual clock doesn't
+        // move on while the event loop is still catching up
+        let guard = self.clock.schedule_with_date(at, move || {
+            let (done_tx, done_rx) = channel();
+            if tx.send(Message::Timer(generation, event, done_tx)).is_ok() {
+                done_rx.recv().ok();
+            }
+        });
+        self.timer_guard = Some(guard);
+    }
+
+    fn on_timer(&mut self, event: TimerEvent) {
+        let source = Source::from(Cause::Timeout);
+        let result = match event {
+            TimerEvent::FinishBrew => self.fire(Input::BrewDone, &source),
+            TimerEvent::NextStep => {
+                self.update_step();
+                self.time_brew();
+                Ok(())
+            }
+            TimerEvent::KeepWarmOver => self.fire(Input::KeepWarmOver, &source),
+            TimerEvent::ReadyExpired => self.fire(Input::ReadyExpired, &source),
+            TimerEvent::Due(Due::Scheduled(id), _) => self.fire_scheduled(id),
+            TimerEvent::Due(Due::Rule(id), at) => self.fire_rule(id, at),
+        };
+        if let Err(e) = result {
+            info!("skipping {:?}: {}", event, e);
+        }
+    }
+
+    /** Why `input` has no row in the current state */
+    fn refusal(&self, input: Input) -> CoffeepotError {
+        match (self.state, input) {
+            (PotState::Fault, _) => CoffeepotError::Faulted,
+            (PotState::Shutdown, _) => CoffeepotError::ShutDown,
+            (_, Input::Schedule) => CoffeepotError::NotArmed,
+            (state, _) => CoffeepotError::InvalidInState(state),
+        }
+    }
+
+    /** Checks `input` against the table before changing anything for it */
+    fn accepts(&self, input: Input) -> Result<(), CoffeepotError> {
+        match machine::accepts(self.state, input) {
+            true => Ok(()),
+            false => Err(self.refusal(input)),
+        }
+    }
+
+    fn holds(&self, guard: Guard) -> bool {
+        match guard {
+            Guard::Always => true,
+            Guard::SomethingDue => self.next_due().is_some(),
+            Guard::NothingDue => self.next_due().is_none(),
+            Guard::KeepWarmSet => self.brew_keep_warm() > Duration::zero(),
+            Guard::NoKeepWarm => self.brew_keep_warm() <= Duration::zero(),
+        }
+    }
+
+    /** Takes the row of the transition table matching `input`, if any */
+    fn fire(&mut self, input: Input, source: &Source) -> Result<(), CoffeepotError> {
+        self.prune_schedule();
+        let row = machine::find(self.state, input, |guard| self.holds(guard))
+            .ok_or_else(|| self.refusal(input))?;
+        debug!("{:?} in {:?} takes {:?}", input, self.state, row);
+        let stayed = row.to == self.state;
+        if stayed {
+            // the schedule may have changed
+            self.save();
+        } else {
+            self.change_state(row.to, source);
+        }
+        match row.action {
+            Action::Nothing => (),
+            Action::StartBrew => {
+                self.time_brew();
+                // a new brew while Active starts its stages over
+                if stayed {
+                    self.notify(self.state, source);
+                }
+            }
+            Action::WaitForDue => match (self.next_due(), self.ready_expires_at()) {
+                // the grounds would be stale by the time it's due
+                (Some((_, at)), Some(expires_at)) if expires_at <= at => {
+                    debug!("ready expires at {:#?}, before the next activation", expires_at);
+                    self.set_timer(expires_at, TimerEvent::ReadyExpired);
+                }
+                (Some((due, at)), _) => {
+                    info!("{:?} set to {:#?}", due, at);
+                    self.set_timer(at, TimerEvent::Due(due, at));
+                    if let Due::Scheduled(id) = due {
+                        self.due_entry = Some(id);
+                    }
+                }
+                (None, _) => (),
+            },
+            Action::StartReadyExpiry => {
+                if let Some(expires_at) = self.ready_expires_at() {
+                    debug!("ready expires at {:#?}", expires_at);
+                    self.set_timer(expires_at, TimerEvent::ReadyExpired);
+                }
+            }
+            Action::StartKeepWarm => {
+                let keep_warm_time = self.brew_keep_warm();
+                info!("keeping warm for {}", keep_warm_time);
+                let now = self.clock.now();
+                let over_at = now.checked_add_signed(keep_warm_time).unwrap_or_else(|| {
+                    warn!("keeping warm for {} is out of range, stopping now", keep_warm_time);
+                    now
+                });
+                self.set_timer(over_at, TimerEvent::KeepWarmOver);
             }
-            _ => (),
         }
+        Ok(())
     }
 
-    fn change_state(&mut self, new_state: PotState) {
+    fn change_state(&mut self, new_state: PotState, source: &Source) {
         debug!("changing coffeepot state to {:?}", new_state);
-        // Cancel timer even if new state is the same, since a new timer will be
-        // instantiated if there is currently one
         self.cancel_timer();
-        if new_state == self.state {
-            return;
+        if !new_state.powers_relay() {
+            self.activation = None;
         }
+        let from = self.state;
+        let was_armed = self.is_armed();
         self.state = new_state;
-        self.tx.send(new_state).unwrap();
+        match (was_armed, self.is_armed()) {
+            // unless restoring when it was armed before a restart
+            (false, true) => {
+                self.armed_at.get_or_insert(self.clock.now());
+            }
+            (_, false) => self.armed_at = None,
+            _ => (),
+        }
+        // Shutdown isn't saved, so that a restart picks up where we left off
+        if new_state != PotState::Shutdown {
+            self.save();
+        }
+        if from != new_state {
+            self.notify(from, source);
+        }
+    }
+
+    /** From `from` to the current state */
+    fn transition(&self, from: PotState, source: &Source) -> Transition {
+        let step = self.step();
+        Transition {
+            from,
+            to: self.state,
+            cause: source.cause,
+            origin: source.origin.clone(),
+            profile: self.current_profile(),
+            stage: step.stage,
+            outputs: step.outputs,
+            at: self.clock.now(),
+        }
+    }
+
+    /** The profile of the brew in progress, or of the one the pot is Waiting for */
+    fn current_profile(&self) -> Option<String> {
+        match self.state {
+            PotState::Waiting => self.scheduled_activation()?.profile,
+            _ => self.activation.as_ref()?.profile.clone(),
+        }
+    }
+
+    fn brew_profile(&self) -> Option<&BrewProfile> {
+        let name = self.activation.as_ref()?.profile.as_ref()?;
+        self.profiles.iter().find(|p| &p.name == name)
+    }
+
+    /** The keep warm time of the current brew's profile, or the default */
+    fn brew_keep_warm(&self) -> Duration {
+        self.brew_profile()
+            .and_then(|profile| profile.keep_warm_time)
+            .unwrap_or(self.keep_warm_time)
+    }
+
+    /**
+     * Where the brew in progress is: the profile's stages run one after the
+     * other from the start of the brew, followed by the brew stage with its
+     * pulse sequence on the brewer.
+     */
+    fn step(&self) -> Step {
+        let brewing = |outputs: Vec<String>, next| Step {
+            stage: Some(BREW_STAGE.to_string()),
+            outputs,
+            next,
+        };
+        let activation = match (self.state, &self.activation) {
+            (PotState::Active, Some(activation)) => activation,
+            (PotState::Active, None) => return brewing(vec![BREWER.to_string()], None),
+            (PotState::KeepWarm, _) => {
+                return Step {
+                    outputs: vec![BREWER.to_string()],
+                    ..Step::default()
+                }
+            }
+            _ => return Step::default(),
+        };
+        let profile = match self.brew_profile() {
+            Some(profile) => profile,
+            None => return brewing(vec![BREWER.to_string()], None),
+        };
+        let now = self.clock.now();
+        let mut step_at = activation.at;
+        for stage in &profile.stages {
+            step_at = step_at + stage.length;
+            if step_at > now {
+                return Step {
+                    stage: Some(stage.output.clone()),
+                    outputs: vec![stage.output.clone()],
+                    next: Some(step_at),
+                };
+            }
+        }
+        for (i, length) in profile.pulses.iter().enumerate() {
+            let outputs = if i % 2 == 0 { vec![BREWER.to_string()] } else { Vec::new() };
+            match step_at.checked_add_signed(*length) {
+                Some(at) if at > now => return brewing(outputs, Some(at)),
+                Some(at) => step_at = at,
+                // a pulse too long to end lasts the rest of the brew
+                None => return brewing(outputs, None),
+            }
+        }
+        brewing(vec![BREWER.to_string()], None)
+    }
+
+    /** Times the next stage or pulse, or the end of the brew */
+    fn time_brew(&mut self) {
+        let end = match &self.activation {
+            Some(activation) => activation.at + activation.brew_time,
+            None => return,
+        };
+        match self.step().next {
+            Some(step_at) if step_at < end => self.set_timer(step_at, TimerEvent::NextStep),
+            _ => self.set_timer(end, TimerEvent::FinishBrew),
+        }
+    }
+
+    /** Tells subscribers about a new stage or pulse, without changing state */
+    fn update_step(&mut self) {
+        let step = self.step();
+        if step.stage != self.stage {
+            debug!("stage {:?}", step.stage);
+            self.notify(self.state, &Cause::Stage.into());
+        } else if step.outputs != self.outputs {
+            debug!("pulse, outputs on: {:?}", step.outputs);
+            self.notify(self.state, &Cause::Pulse.into());
+        }
+    }
+
+    /** Subscribers whose thread has died are dropped */
+    fn notify(&mut self, from: PotState, source: &Source) {
+        let transition = self.transition(from, source);
+        self.stage = transition.stage.clone();
+        self.outputs = transition.outputs.clone();
+        self.subscribers.retain(|subscriber| {
+            let alive = subscriber.tx.send(transition.clone()).is_ok();
+            if !alive {
+                error!("subscriber {} died, unsubscribing", subscriber.name);
+            }
+            alive
+        });
+    }
+
+    /** When the pot disarms itself, unless it brews first. Never if that's out of range */
+    fn ready_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.armed_at?.checked_add_signed(self.ready_expiry?)
+    }
+
+    fn is_armed(&self) -> bool {
+        self.state == PotState::Ready || self.state == PotState::Waiting
+    }
+
+    /**
+     * Whether an entry is still to come, or is the one the timer is set for
+     * and has just not fired yet
+     */
+    fn is_current(&self, entry: &ScheduledActivation, now: DateTime<Utc>) -> bool {
+        entry.activation.at > now || self.due_entry == Some(entry.id)
+    }
+
+    fn prune_schedule(&mut self) {
+        let now = self.clock.now();
+        let schedule = std::mem::take(&mut self.schedule);
+        self.schedule = schedule.into_iter().filter(|e| self.is_current(e, now)).collect();
+        self.schedule.sort_by_key(|e| e.activation.at);
+        self.skipped.retain(|(_, at)| *at > now);
+    }
+
+    /** The earliest of the next scheduled activation and the next rule */
+    fn next_due(&self) -> Option<(Due, DateTime<Utc>)> {
+        let now = self.clock.now();
+        let scheduled = self
+            .schedule
+            .first()
+            .map(|e| (Due::Scheduled(e.id), e.activation.at));
+        let rules = self.rules.iter().filter_map(|rule| {
+            let mut at = rule.next_after(now)?;
+            while self.skipped.contains(&(rule.id, at)) {
+                at = rule.next_after(at)?;
+            }
+            Some((Due::Rule(rule.id), at))
+        });
+        scheduled.into_iter().chain(rules).min_by_key(|(_, at)| *at)
+    }
+
+    /** The activation the pot is Waiting for */
+    fn scheduled_activation(&self) -> Option<Activation> {
+        if self.state != PotState::Waiting {
+            return None;
+        }
+        match self.next_due()? {
+            (Due::Scheduled(id), _) => self.schedule.iter().find(|e| e.id == id).map(|e| e.activation.clone()),
+            (Due::Rule(id), at) => self.rules.iter().find(|r| r.id == id).map(|rule| Activation {
+                at,
+                brew_time: rule.brew_time,
+                profile: None,
+            }),
+        }
+    }
+
+    /**
+     * Removes the activation the pot is Waiting for from the schedule, or
+     * skips the occurrence if it comes from a weekly rule.
+     */
+    fn take_pending(&mut self) -> Option<Activation> {
+        let pending = self.scheduled_activation()?;
+        match self.next_due()? {
+            (Due::Scheduled(id), _) => self.schedule.retain(|e| e.id != id),
+            (Due::Rule(id), at) => self.skipped.push((id, at)),
+        }
+        Some(pending)
+    }
+
+    fn save_rules(&self) {
+        if let Some(store) = &self.rules_store {
+            if let Err(e) = store.save(&self.rules) {
+                error!("could not save weekly rules: {}", e);
+            }
+        }
+    }
+
+    fn save(&self) {
+        if let Some(store) = &self.store {
+            let saved = SavedState {
+                state: self.state,
+                activation: self.activation.clone(),
+                schedule: self.schedule.clone(),
+                armed_at: self.armed_at,
+                skipped: self.skipped.clone(),
+                next_id: self.next_id,
+            };
+            if let Err(e) = store.save(&saved) {
+                error!("could not save coffeepot state: {}", e);
+            }
+        }
+    }
+
+    fn restore(&mut self, saved: SavedState, policy: RestorePolicy) {
+        info!("restoring {:?} with policy {:?}", saved, policy);
+        let source = Source::from(Cause::Startup);
+        let now = self.clock.now();
+        self.next_id = saved.schedule.iter().map(|e| e.id + 1).fold(saved.next_id, u32::max);
+        if policy != RestorePolicy::FallBackToReady {
+            self.schedule = saved.schedule.clone();
+            self.skipped = saved.skipped.clone();
+        }
+        let in_window =
+            |a: &Activation| a.at <= now && a.at.checked_add_signed(a.brew_time).is_some_and(|end| end > now);
+        let missed = saved
+            .schedule
+            .iter()
+            .find(|e| in_window(&e.activation))
+            .map(|e| e.activation.clone());
+        let result = match (saved.state, saved.activation.clone(), missed) {
+            (PotState::Active, Some(activation), _) | (PotState::Waiting, _, Some(activation))
+                if policy == RestorePolicy::Resume && in_window(&activation) =>
+            {
+                self.activation = Some(activation);
+                self.fire(Input::Activate, &source)
+            }
+            // latched until explicitly cleared
+            (PotState::Fault, _, _) => self.fire(Input::Fault, &source),
+            // a missed activation means the pot is still armed
+            (PotState::Ready, _, _) | (PotState::Waiting, _, _) => {
+                self.armed_at = saved.armed_at;
+                self.fire(Input::ToggleReady, &source)
+            }
+            _ => self.fire(Input::ScheduleChanged, &source),
+        };
+        if let Err(e) = result {
+            error!("could not restore {:?}: {}", saved.state, e);
+        }
+    }
+
+    /** Brews from `at`, when the occurrence was due, however late the timer fired */
+    fn fire_rule(&mut self, id: u32, at: DateTime<Utc>) -> Result<(), CoffeepotError> {
+        let brew_time = match self.rules.iter().find(|r| r.id == id) {
+            Some(rule) => rule.brew_time,
+            None => return Err(CoffeepotError::UnknownId(id)),
+        };
+        self.accepts(Input::Due)?;
+        if let Err(e) = self.ensure_brew_time(brew_time) {
+            // wait for whatever else is due rather than for this rule again
+            self.skipped.push((id, at));
+            self.fire(Input::ScheduleChanged, &Cause::Schedule.into())?;
+            return Err(e);
+        }
+        info!("weekly rule {} due", id);
+        self.activation = Some(Activation {
+            at,
+            brew_time,
+            profile: None,
+        });
+        self.fire(Input::Due, &Cause::Schedule.into())
+    }
+
+    fn fire_scheduled(&mut self, id: u32) -> Result<(), CoffeepotError> {
+        let position = self.schedule.iter().position(|e| e.id == id);
+        let entry = match position {
+            Some(i) => self.schedule.remove(i),
+            None => return Err(CoffeepotError::UnknownId(id)),
+        };
+        if let Err(e) = self.accepts(Input::Due) {
+            self.save();
+            return Err(e);
+        }
+        info!("scheduled activation {} due", id);
+        self.activation = Some(entry.activation);
+        self.fire(Input::Due, &Cause::Schedule.into())
+    }
+
+    /** An activation at `at` for `brew`, which must not be longer than the maximum */
+    fn new_activation(&self, at: DateTime<Utc>, brew: Brew) -> Result<Activation, CoffeepotError> {
+        let (brew_time, profile) = match brew {
+            Brew::Default => (self.brew_time, None),
+            Brew::For(time) => (time, None),
+            Brew::Profile(name) => match self.profiles.iter().find(|p| p.name == name) {
+                Some(profile) => (profile.brew_time, Some(name)),
+                None => return Err(CoffeepotError::UnknownProfile),
+            },
+        };
+        self.ensure_brew_time(brew_time)?;
+        Self::ensure_in_range(at, brew_time)?;
+        Ok(Activation { at, brew_time, profile })
+    }
+
+    fn ensure_brew_time(&self, time: Duration) -> Result<(), CoffeepotError> {
+        if time <= Duration::zero() || time > self.max_brew_time {
+            return Err(CoffeepotError::InvalidBrewTime(self.max_brew_time));
+        }
+        Ok(())
+    }
+
+    /** The end of a brew starting at `at` must be representable too */
+    fn ensure_in_range(at: DateTime<Utc>, brew_time: Duration) -> Result<(), CoffeepotError> {
+        at.checked_add_signed(brew_time).ok_or(CoffeepotError::OutOfRange)?;
+        Ok(())
+    }
+
+    fn ensure_future(&self, at: DateTime<Utc>) -> Result<(), CoffeepotError> {
+        if at < self.clock.now() {
+            return Err(CoffeepotError::InPast);
+        }
+        Ok(())
+    }
+
+    fn push_schedule(&mut self, activation: Activation, source: &Source) -> Result<u32, CoffeepotError> {
+        self.accepts(Input::Schedule)?;
+        self.ensure_future(activation.at)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.schedule.push(ScheduledActivation { id, activation });
+        self.fire(Input::Schedule, source)?;
+        Ok(id)
     }
 }
 
+/** Why the state changed */
+#[derive(PartialEq, Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cause {
+    // only the Pi has buttons
+    #[cfg_attr(not(target_arch = "arm"), allow(dead_code))]
+    Button,
+    Mqtt,
+    Terminal,
+    /** A scheduled activation or weekly rule fell due */
+    Schedule,
+    /** A brew, keep warm or the ready expiry ran out */
+    Timeout,
+    /** The brew's pulse sequence switched the relay, staying Active */
+    Pulse,
+    /** The brew moved on to its next stage, staying Active */
+    Stage,
+    Watchdog,
+    /** Restored after a restart, or the state a new subscriber starts from */
+    Startup,
+    Shutdown,
+}
+
+/** The cause of a request, and where it came from if there is more to say */
+#[derive(PartialEq, Clone, Debug)]
+pub struct Source {
+    pub cause: Cause,
+    pub origin: Option<String>,
+}
+
+impl Source {
+    /** A command received on the MQTT topic `topic` */
+    pub fn mqtt(topic: &str) -> Self {
+        Source {
+            cause: Cause::Mqtt,
+            origin: Some(topic.to_string()),
+        }
+    }
+}
+
+impl From<Cause> for Source {
+    fn from(cause: Cause) -> Self {
+        Source { cause, origin: None }
+    }
+}
+
+/** Delivered to every subscriber, in order, every time the state changes */
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct Transition {
+    pub from: PotState,
+    pub to: PotState,
+    pub cause: Cause,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /** The brew profile of the brew in progress or being Waited for */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /** The stage of the brew in progress, its profile's stages and then `brew` */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    /** The outputs that should be on, which is none between pulses */
+    pub outputs: Vec<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl Transition {
+    /** A stage or pulse of the brew in progress rather than a change of state */
+    pub fn is_step(&self) -> bool {
+        self.cause == Cause::Pulse || self.cause == Cause::Stage
+    }
+
+    /** A new brew replaced the one in progress, cutting its stage short */
+    pub fn is_restart(&self) -> bool {
+        self.from == PotState::Active && self.to == PotState::Active && !self.is_step()
+    }
+
+    /** The pot was disarmed by the ready expiry rather than by hand */
+    pub fn is_ready_expiry(&self) -> bool {
+        matches!(self.from, PotState::Ready | PotState::Waiting) && self.to == PotState::Idle && self.cause == Cause::Timeout
+    }
+}
+
+struct Subscriber {
+    id: u32,
+    name: String,
+    tx: Sender<Transition>,
+    handle: JoinHandle<()>,
+}
+
 /**
- * Keeps the callback function to a single thread, and isolates the callback
- * type from polluting all of the structs with trait bounds.
- * Not strictly needed.
+ * Runs each subscriber on a thread of its own, so that a slow or panicking
+ * subscriber doesn't hold up the state machine or the other subscribers.
  */
-fn callback_handler<B: FnMut(PotState) + Send + 'static>(
-    mut cb: B,
-) -> (Sender<PotState>, thread::JoinHandle<()>) {
-    let (tx, rx) = channel();
-    (
-        tx,
-        thread::spawn(move || loop {
-            let state = rx.recv().unwrap();
-            debug!("received coffeepot state {:?}", state);
-            cb(state);
-            if state == PotState::Shutdown {
-                info!("exiting coffeepot callback handler loop");
+fn subscriber_thread<F>(name: String, mut f: F) -> (Sender<Transition>, JoinHandle<()>)
+where
+    F: FnMut(Transition) + Send + 'static,
+{
+    let (tx, rx) = channel::<Transition>();
+    let handle = thread::spawn(move || {
+        for transition in rx {
+            debug!("{} received {:?}", name, transition);
+            let shutdown = transition.to == PotState::Shutdown;
+            f(transition);
+            if shutdown {
                 break;
             }
-        }),
-    )
+        }
+        info!("exiting subscriber {} loop", name);
+    });
+    (tx, handle)
 }
 
-pub struct Coffeepot {
-    props: Arc<Mutex<CoffeepotInternals>>,
+/**
+ * Handle to the state machine, which runs on an event loop thread of its own.
+ * Every call waits for the event loop to handle it, and once shut down calls
+ * return a default value instead.
+ */
+pub struct Coffeepot<S: Scheduler = timer::Timer> {
+    tx: Sender<Message<S>>,
 }
 
 impl Coffeepot {
-    pub fn new<B: FnMut(PotState) + Send + 'static>(cb: B) -> Self {
-        let (tx, _) = callback_handler(cb);
-        // send initial message with the starting state
-        tx.send(PotState::Idle)
-            .map_err(|_| error!("error sending initial idle state"))
-            .expect("error sending initial idle state");
+    pub fn new() -> Self {
+        Coffeepot::with_scheduler(timer::Timer::new())
+    }
+
+    pub fn builder() -> CoffeepotBuilder {
+        CoffeepotBuilder::default()
+    }
+}
+
+/** Durations the state machine starts out with */
+#[derive(Clone, Debug)]
+pub struct CoffeepotBuilder {
+    brew_time: Duration,
+    max_brew_time: Duration,
+    keep_warm_time: Duration,
+    ready_expiry: Option<Duration>,
+    profiles: Vec<BrewProfile>,
+}
+
+impl Default for CoffeepotBuilder {
+    fn default() -> Self {
+        CoffeepotBuilder {
+            brew_time: Duration::minutes(90),
+            max_brew_time: Duration::minutes(120),
+            keep_warm_time: Duration::zero(),
+            ready_expiry: None,
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl CoffeepotBuilder {
+    /** Used by commands that don't ask for a brew time of their own */
+    pub fn brew_time(mut self, time: Duration) -> Self {
+        self.brew_time = time;
+        self
+    }
+
+    /** Longer brews are refused, including the default brew time */
+    pub fn max_brew_time(mut self, time: Duration) -> Self {
+        self.max_brew_time = time;
+        self
+    }
+
+    /** How long to stay in KeepWarm after a timed brew, zero skips it */
+    pub fn keep_warm_time(mut self, time: Duration) -> Self {
+        self.keep_warm_time = time;
+        self
+    }
+
+    /** Disarms the pot after it has been armed for `time` without brewing */
+    pub fn ready_expiry(mut self, time: Duration) -> Self {
+        self.ready_expiry = Some(time);
+        self
+    }
+
+    /** Brew profiles commands can ask for by name */
+    pub fn profiles(mut self, profiles: Vec<BrewProfile>) -> Self {
+        self.profiles = profiles;
+        self
+    }
+
+    pub fn build(self) -> Coffeepot {
+        self.build_with_scheduler(timer::Timer::new())
+    }
+
+    /** Runs the state machine on the given clock instead of wall time */
+    pub fn build_with_scheduler<S: Scheduler>(self, clock: S) -> Coffeepot<S> {
+        let mut brew_time = self.brew_time;
+        if brew_time > self.max_brew_time {
+            warn!("default brew time {} is longer than the maximum, using {}", brew_time, self.max_brew_time);
+            brew_time = self.max_brew_time;
+        }
+        let (tx, rx) = channel();
         let pot = CoffeepotInternals {
             state: PotState::Idle,
+            activation: None,
+            schedule: Vec::new(),
+            next_id: 1,
+            rules: Vec::new(),
+            next_rule_id: 1,
+            skipped: Vec::new(),
+            due_entry: None,
+            brew_time,
+            max_brew_time: self.max_brew_time,
+            profiles: self.profiles,
+            keep_warm_time: self.keep_warm_time,
+            armed_at: None,
+            ready_expiry: self.ready_expiry,
             timer_guard: None,
-            clock: timer::Timer::new(),
-            tx,
+            timer_generation: 0,
+            clock,
+            tx: tx.clone(),
+            subscribers: Vec::new(),
+            next_subscriber_id: 1,
+            stage: None,
+            outputs: Vec::new(),
+            store: None,
+            rules_store: None,
         };
+        thread::spawn(move || pot.run(rx));
+        Coffeepot { tx }
+    }
+}
+
+impl Default for Coffeepot {
+    fn default() -> Self {
+        Coffeepot::new()
+    }
+}
+
+impl<S: Scheduler> Clone for Coffeepot<S> {
+    fn clone(&self) -> Self {
         Coffeepot {
-            props: Arc::new(Mutex::new(pot)),
+            tx: self.tx.clone(),
         }
     }
+}
+
+impl<S: Scheduler> Coffeepot<S> {
+    /** Runs the state machine on the given clock instead of wall time */
+    pub fn with_scheduler(clock: S) -> Self {
+        CoffeepotBuilder::default().build_with_scheduler(clock)
+    }
 
-    pub fn clone(&self) -> Self {
-        Coffeepot {
-            props: self.props.clone(),
+    /**
+     * Runs `f` on the event loop and waits for the result, or returns `None`
+     * if the pot has shut down. Must never be called from the event loop.
+     */
+    fn call<R, F>(&self, f: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut CoffeepotInternals<S>) -> R + Send + 'static,
+    {
+        let (reply_tx, reply_rx) = channel();
+        let job: Job<S> = Box::new(move |pot| {
+            reply_tx.send(f(pot)).ok();
+        });
+        if self.tx.send(Message::Call(job)).is_err() {
+            debug!("coffeepot has shut down");
+            return None;
         }
+        reply_rx.recv().ok()
+    }
+
+    /**
+     * Runs `f` on the event loop, and returns the transition from the state
+     * before to the state after it.
+     */
+    fn request<F>(&self, source: impl Into<Source>, f: F) -> Result<Transition, CoffeepotError>
+    where
+        F: FnOnce(&mut CoffeepotInternals<S>, &Source) -> Result<(), CoffeepotError> + Send + 'static,
+    {
+        self.request_with(source, f).map(|((), transition)| transition)
+    }
+
+    /** Like `request`, for requests that also return the id of what they created */
+    fn request_with<T, F>(&self, source: impl Into<Source>, f: F) -> Result<(T, Transition), CoffeepotError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut CoffeepotInternals<S>, &Source) -> Result<T, CoffeepotError> + Send + 'static,
+    {
+        let source = source.into();
+        self.call(move |pot| {
+            let from = pot.state;
+            let created = f(pot, &source)?;
+            Ok((created, pot.transition(from, &source)))
+        })
+        .unwrap_or(Err(CoffeepotError::ShutDown))
+    }
+
+    /**
+     * Registers `f` to be called with every transition, starting with one from
+     * and to the current state. Returns an id for `unsubscribe`.
+     */
+    pub fn subscribe<F: FnMut(Transition) + Send + 'static>(&self, name: &str, f: F) -> u32 {
+        let name = name.to_string();
+        let (tx, handle) = subscriber_thread(name.clone(), f);
+        self.call(move |pot| {
+            let id = pot.next_subscriber_id;
+            pot.next_subscriber_id += 1;
+            tx.send(pot.transition(pot.state, &Cause::Startup.into())).ok();
+            info!("subscribed {} as {}", name, id);
+            pot.subscribers.push(Subscriber {
+                id,
+                name,
+                tx,
+                handle,
+            });
+            id
+        })
+        .unwrap_or(0)
+    }
+
+    /** Returns false if there was no such subscriber */
+    pub fn unsubscribe(&self, id: u32) -> bool {
+        self.call(move |pot| {
+            let before = pot.subscribers.len();
+            pot.subscribers.retain(|subscriber| subscriber.id != id);
+            pot.subscribers.len() != before
+        })
+        .unwrap_or(false)
     }
 
     pub fn current_state(&self) -> PotState {
         debug!("fetching current state");
-        self.props.lock().unwrap().state
-    }
-
-    pub fn activate(&self, time: Duration) {
-        let mut attrs = self.props.lock().unwrap();
-        info!("activating for {}", time);
-        attrs.change_state(PotState::Active);
-        let clone = self.clone();
-        let guard = attrs
-            .clock
-            .schedule_with_delay(time, move || clone.inactivate());
-        attrs.timer_guard = Some(guard);
-    }
-
-    pub fn activate_delayed<Tz: TimeZone>(&self, time: Duration, activation_time: DateTime<Tz>) {
-        let mut attrs = self.props.lock().unwrap();
-        if attrs.state != PotState::Ready && attrs.state != PotState::Waiting {
-            debug!("got activate delayed in non-ready state");
-            return;
-        }
-        attrs.change_state(PotState::Waiting);
-        info!("activation time set to {:#?}", activation_time);
-        let clone = self.clone();
-        let guard = attrs
-            .clock
-            .schedule_with_date(activation_time, move || clone.activate(time));
-        attrs.timer_guard = Some(guard);
-    }
-
-    pub fn inactivate(&self) {
-        let mut attrs = self.props.lock().unwrap();
-        info!("inactivating");
-        attrs.change_state(PotState::Idle);
-    }
-
-    pub fn toggle_ready(&self) {
-        let mut attrs = self.props.lock().unwrap();
-        info!("toggling ready");
-        match attrs.state {
-            PotState::Idle => {
-                attrs.change_state(PotState::Ready);
+        self.call(|pot| pot.state).unwrap_or(PotState::Shutdown)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.call(|pot| pot.clock.now()).unwrap_or_else(Utc::now)
+    }
+
+    /** The activation the pot is Waiting for, or `None` when not Waiting */
+    pub fn scheduled_activation(&self) -> Option<Activation> {
+        self.call(|pot| pot.scheduled_activation()).flatten()
+    }
+
+    /** How long the current brew has left, or `None` unless Active on a timed brew */
+    pub fn remaining_active(&self) -> Option<Duration> {
+        self.call(|pot| match (pot.state, &pot.activation) {
+            (PotState::Active, Some(activation)) => {
+                let left = activation.at + activation.brew_time - pot.clock.now();
+                Some(std::cmp::max(left, Duration::zero()))
+            }
+            _ => None,
+        })
+        .flatten()
+    }
+
+    /**
+     * Restores the state saved in `store` according to `policy`, and keeps
+     * saving to it on every state change from here on.
+     */
+    pub fn restore(&self, store: StateFile, policy: RestorePolicy) {
+        let saved = store.load().unwrap_or_else(|e| {
+            error!("could not load coffeepot state: {}", e);
+            None
+        });
+        self.call(move |pot| {
+            pot.store = Some(store);
+            match saved {
+                Some(saved) => pot.restore(saved, policy),
+                None => info!("no saved state, starting idle"),
+            }
+        });
+    }
+
+    pub fn activate(&self, brew: impl Into<Brew>, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        let brew = brew.into();
+        self.request(source, move |pot, source| {
+            pot.accepts(Input::Activate)?;
+            let activation = pot.new_activation(pot.clock.now(), brew)?;
+            info!("activating for {}", activation.brew_time);
+            pot.activation = Some(activation);
+            pot.fire(Input::Activate, source)
+        })
+    }
+
+    /** Replaces the whole schedule with a single activation */
+    pub fn activate_delayed<Tz: TimeZone>(
+        &self,
+        brew: impl Into<Brew>,
+        activation_time: DateTime<Tz>,
+        source: impl Into<Source>,
+    ) -> Result<Transition, CoffeepotError> {
+        let at = activation_time.with_timezone(&Utc);
+        let brew = brew.into();
+        self.request(source, move |pot, source| {
+            pot.accepts(Input::Schedule)?;
+            let activation = pot.new_activation(at, brew)?;
+            pot.ensure_future(at)?;
+            pot.schedule.clear();
+            pot.push_schedule(activation, source).map(|_| ())
+        })
+    }
+
+    /**
+     * Adds an activation to the schedule and returns its id, along with the
+     * transition. Every entry still needs the pot to be armed when it falls due.
+     */
+    pub fn schedule<Tz: TimeZone>(
+        &self,
+        brew: impl Into<Brew>,
+        activation_time: DateTime<Tz>,
+        source: impl Into<Source>,
+    ) -> Result<(u32, Transition), CoffeepotError> {
+        let at = activation_time.with_timezone(&Utc);
+        let brew = brew.into();
+        self.request_with(source, move |pot, source| {
+            pot.accepts(Input::Schedule)?;
+            let activation = pot.new_activation(at, brew)?;
+            pot.push_schedule(activation, source)
+        })
+    }
+
+    pub fn cancel_scheduled(&self, id: u32, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, move |pot, source| {
+            pot.accepts(Input::ScheduleChanged)?;
+            let before = pot.schedule.len();
+            pot.schedule.retain(|e| e.id != id);
+            if pot.schedule.len() == before {
+                return Err(CoffeepotError::UnknownId(id));
+            }
+            info!("cancelled scheduled activation {}", id);
+            pot.fire(Input::ScheduleChanged, source)
+        })
+    }
+
+    /** Drops the activation the pot is Waiting for, staying armed */
+    pub fn cancel_pending(&self, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, |pot, source| {
+            pot.accepts(Input::CancelPending)?;
+            pot.prune_schedule();
+            if let Some(pending) = pot.take_pending() {
+                info!("cancelled pending activation at {}", pending.at);
+            }
+            pot.fire(Input::CancelPending, source)
+        })
+    }
+
+    /**
+     * Moves the activation the pot is Waiting for by `offset`, which is
+     * negative to brew earlier, and returns its id in the schedule along with
+     * the transition. A
+     * scheduled activation keeps its id, while a weekly rule's occurrence is
+     * skipped and scheduled on its own. Moving it into the past is refused.
+     */
+    pub fn shift_pending(&self, offset: Duration, source: impl Into<Source>) -> Result<(u32, Transition), CoffeepotError> {
+        self.request_with(source, move |pot, source| {
+            pot.accepts(Input::ShiftPending)?;
+            pot.prune_schedule();
+            let (due, _) = pot.next_due().ok_or(CoffeepotError::InvalidInState(pot.state))?;
+            let pending = pot.scheduled_activation().ok_or(CoffeepotError::InvalidInState(pot.state))?;
+            let at = pending.at.checked_add_signed(offset).ok_or(CoffeepotError::OutOfRange)?;
+            CoffeepotInternals::<S>::ensure_in_range(at, pending.brew_time)?;
+            pot.ensure_future(at)?;
+            info!("moving pending activation from {} to {}", pending.at, at);
+            let id = match due {
+                Due::Scheduled(id) => {
+                    if let Some(entry) = pot.schedule.iter_mut().find(|e| e.id == id) {
+                        entry.activation.at = at;
+                    }
+                    id
+                }
+                Due::Rule(_) => {
+                    pot.take_pending();
+                    let id = pot.next_id;
+                    pot.next_id += 1;
+                    pot.schedule.push(ScheduledActivation {
+                        id,
+                        activation: Activation { at, ..pending },
+                    });
+                    id
+                }
+            };
+            pot.fire(Input::ShiftPending, source)?;
+            Ok(id)
+        })
+    }
+
+    /** Loads the weekly rules from `store`, and saves to it on every change */
+    pub fn load_rules(&self, store: RulesFile) {
+        let rules = store.load().unwrap_or_else(|e| {
+            error!("could not load weekly rules: {}", e);
+            Vec::new()
+        });
+        self.call(move |pot| {
+            info!("loaded {} weekly rules", rules.len());
+            pot.next_rule_id = rules.iter().map(|r| r.id + 1).max().unwrap_or(1);
+            pot.rules = rules
+                .into_iter()
+                .filter(|rule| match pot.ensure_brew_time(rule.brew_time) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("dropping weekly rule {} {}: {}", rule.id, rule, e);
+                        false
+                    }
+                })
+                .collect();
+            pot.rules_store = Some(store);
+            if let Err(e) = pot.fire(Input::ScheduleChanged, &Cause::Startup.into()) {
+                error!("could not wait for weekly rules: {}", e);
+            }
+        });
+    }
+
+    /**
+     * Adds a weekly rule and returns its id, along with the transition. Unlike
+     * scheduled activations, rules can be added while not armed.
+     */
+    pub fn add_rule(&self, rule: WeeklyRule, source: impl Into<Source>) -> Result<(u32, Transition), CoffeepotError> {
+        self.request_with(source, move |pot, source| {
+            pot.accepts(Input::ScheduleChanged)?;
+            pot.ensure_brew_time(rule.brew_time)?;
+            let id = pot.next_rule_id;
+            pot.next_rule_id += 1;
+            pot.rules.push(rule.with_id(id));
+            info!("added weekly rule {}: {}", id, rule);
+            pot.save_rules();
+            pot.fire(Input::ScheduleChanged, source)?;
+            Ok(id)
+        })
+    }
+
+    pub fn remove_rule(&self, id: u32, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, move |pot, source| {
+            pot.accepts(Input::ScheduleChanged)?;
+            let before = pot.rules.len();
+            pot.rules.retain(|r| r.id != id);
+            if pot.rules.len() == before {
+                return Err(CoffeepotError::UnknownId(id));
+            }
+            info!("removed weekly rule {}", id);
+            pot.save_rules();
+            pot.fire(Input::ScheduleChanged, source)
+        })
+    }
+
+    pub fn rules(&self) -> Vec<WeeklyRule> {
+        self.call(|pot| pot.rules.clone()).unwrap_or_default()
+    }
+
+    pub fn scheduled(&self) -> Vec<ScheduledActivation> {
+        self.call(|pot| {
+            let now = pot.clock.now();
+            pot.schedule.iter().filter(|e| pot.is_current(e, now)).cloned().collect()
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn inactivate(&self, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, |pot, source| {
+            info!("inactivating");
+            pot.fire(Input::Inactivate, source)
+        })
+    }
+
+    /**
+     * Latches the Fault state, which turns the relay off and refuses everything
+     * until `clear_fault` is called.
+     */
+    pub fn fault(&self, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, |pot, source| {
+            error!("entering fault state");
+            pot.fire(Input::Fault, source)
+        })
+    }
+
+    pub fn clear_fault(&self, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, |pot, source| {
+            info!("clearing fault");
+            pot.fire(Input::ClearFault, source)
+        })
+    }
+
+    pub fn toggle_ready(&self, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, |pot, source| {
+            info!("toggling ready");
+            pot.fire(Input::ToggleReady, source)
+        })
+    }
+
+    pub fn toggle_active(&self, source: impl Into<Source>) -> Result<Transition, CoffeepotError> {
+        self.request(source, |pot, source| {
+            pot.accepts(Input::TogglePower)?;
+            info!("toggling active");
+            // a manual brew lasts until switched off
+            pot.activation = None;
+            pot.fire(Input::TogglePower, source)
+        })
+    }
+
+    /**
+     * Cancels any pending timer, switches the relay off by sending `Shutdown`
+     * to every subscriber, and waits up to `timeout` for their threads to
+     * finish. Returns false if some of them didn't. The event loop exits, and
+     * everything after this is ignored.
+     */
+    pub fn shutdown(&self, timeout: std::time::Duration) -> bool {
+        let subscribers = self
+            .call(|pot| {
+                info!("shutting down from {:?}", pot.state);
+                pot.fire(Input::Shutdown, &Cause::Shutdown.into()).ok();
+                std::mem::take(&mut pot.subscribers)
+            })
+            .unwrap_or_default();
+        let deadline = Instant::now() + timeout;
+        let mut finished = true;
+        for subscriber in subscribers {
+            while !subscriber.handle.is_finished() && Instant::now() < deadline {
+                thread::sleep(std::time::Duration::from_millis(5));
+            }
+            if !subscriber.handle.is_finished() {
+                error!("subscriber {} didn't finish in time", subscriber.name);
+                finished = false;
+                continue;
+            }
+            if subscriber.handle.join().is_err() {
+                error!("subscriber {} panicked", subscriber.name);
+            }
+        }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::VirtualScheduler;
+    use std::sync::Arc;
+
+    fn start() -> DateTime<Utc> {
+        Utc.ymd(2020, 1, 6).and_hms(6, 0, 0)
+    }
+
+    /** A pot on a virtual clock starting at `start()`, and the transitions it makes */
+    fn pot(builder: CoffeepotBuilder) -> (Coffeepot<VirtualScheduler>, VirtualScheduler, Receiver<Transition>) {
+        let clock = VirtualScheduler::new(start());
+        let coffeepot = builder.build_with_scheduler(clock.clone());
+        let (tx, rx) = channel();
+        coffeepot.subscribe("test", move |transition| {
+            tx.send(transition).ok();
+        });
+        assert_eq!(next(&rx).2, Cause::Startup);
+        (coffeepot, clock, rx)
+    }
+
+    /** The next transition a subscriber got, which arrives on a thread of its own */
+    fn next(rx: &Receiver<Transition>) -> (PotState, PotState, Cause, DateTime<Utc>) {
+        let t = rx.recv_timeout(std::time::Duration::from_secs(1)).expect("no transition");
+        (t.from, t.to, t.cause, t.at)
+    }
+
+    #[test]
+    fn advance_runs_timers_set_by_earlier_timers_when_due() {
+        let (coffeepot, clock, rx) = pot(Coffeepot::builder().brew_time(Duration::minutes(20)));
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        coffeepot
+            .schedule(Brew::Default, start() + Duration::minutes(10), Cause::Terminal)
+            .unwrap();
+        clock.advance(Duration::hours(1));
+        assert_eq!(coffeepot.current_state(), PotState::Idle);
+        assert_eq!(next(&rx), (PotState::Idle, PotState::Ready, Cause::Terminal, start()));
+        assert_eq!(next(&rx), (PotState::Ready, PotState::Waiting, Cause::Terminal, start()));
+        let due = start() + Duration::minutes(10);
+        assert_eq!(next(&rx), (PotState::Waiting, PotState::Active, Cause::Schedule, due));
+        let done = due + Duration::minutes(20);
+        assert_eq!(next(&rx), (PotState::Active, PotState::Idle, Cause::Timeout, done));
+        assert_eq!(clock.pending(), 0);
+    }
+
+    #[test]
+    fn armed_pot_brews_when_due_until_switched_off() {
+        let (coffeepot, clock, rx) = pot(Coffeepot::builder());
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        assert_eq!(next(&rx), (PotState::Idle, PotState::Ready, Cause::Terminal, start()));
+        let due = start() + Duration::minutes(5);
+        coffeepot.activate_delayed(Brew::Default, due, Cause::Terminal).unwrap();
+        assert_eq!(next(&rx), (PotState::Ready, PotState::Waiting, Cause::Terminal, start()));
+        assert_eq!(coffeepot.scheduled_activation().map(|a| a.at), Some(due));
+
+        clock.advance(Duration::minutes(4));
+        assert_eq!(coffeepot.current_state(), PotState::Waiting);
+        clock.advance(Duration::minutes(1));
+        assert_eq!(coffeepot.current_state(), PotState::Active);
+        assert_eq!(next(&rx), (PotState::Waiting, PotState::Active, Cause::Schedule, due));
+
+        clock.advance(Duration::minutes(10));
+        coffeepot.inactivate(Cause::Terminal).unwrap();
+        let off = due + Duration::minutes(10);
+        assert_eq!(next(&rx), (PotState::Active, PotState::Idle, Cause::Terminal, off));
+        assert_eq!(clock.pending(), 0);
+    }
+
+    #[test]
+    fn entry_falling_due_is_kept_until_its_timer_fires() {
+        let (coffeepot, clock, rx) = pot(Coffeepot::builder());
+        let coffeepot = Arc::new(coffeepot);
+        let due = start() + Duration::minutes(5);
+        // runs when the entry is due, but before the pot's timer does
+        let early = Arc::clone(&coffeepot);
+        let _guard = clock.schedule_with_date(due, move || {
+            assert_eq!(early.scheduled().len(), 1);
+            assert_eq!(early.scheduled_activation().map(|a| a.at), Some(due));
+            early.schedule(Brew::Default, due + Duration::hours(1), Cause::Terminal).unwrap();
+        });
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        next(&rx);
+        let id = coffeepot.schedule(Brew::Default, due, Cause::Terminal).unwrap().0;
+        next(&rx);
+
+        clock.advance(Duration::minutes(5));
+        assert_eq!(next(&rx), (PotState::Waiting, PotState::Active, Cause::Schedule, due));
+        let left: Vec<_> = coffeepot.scheduled().iter().map(|e| e.id).collect();
+        assert!(!left.contains(&id));
+        assert_eq!(left.len(), 1);
+    }
+
+    #[test]
+    fn brew_switches_off_after_the_default_brew_time() {
+        let (coffeepot, clock, rx) = pot(Coffeepot::builder());
+        coffeepot.activate(Brew::Default, Cause::Terminal).unwrap();
+        assert_eq!(next(&rx), (PotState::Idle, PotState::Active, Cause::Terminal, start()));
+
+        clock.advance(Duration::minutes(89));
+        assert_eq!(coffeepot.current_state(), PotState::Active);
+        assert_eq!(coffeepot.remaining_active(), Some(Duration::minutes(1)));
+        clock.advance(Duration::minutes(1));
+        assert_eq!(coffeepot.current_state(), PotState::Idle);
+        let done = start() + Duration::minutes(90);
+        assert_eq!(next(&rx), (PotState::Active, PotState::Idle, Cause::Timeout, done));
+    }
+
+    #[test]
+    fn ready_expiry_disarms_a_waiting_pot_before_it_brews() {
+        let (coffeepot, clock, rx) = pot(Coffeepot::builder().ready_expiry(Duration::hours(12)));
+        // Friday evening, before a weekend away
+        clock.advance(Duration::days(4) + Duration::hours(14));
+        let friday = start() + Duration::days(4) + Duration::hours(14);
+        coffeepot.add_rule("weekdays 06:15 30".parse().unwrap(), Cause::Terminal).unwrap();
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        assert_eq!(next(&rx), (PotState::Idle, PotState::Waiting, Cause::Terminal, friday));
+
+        clock.advance(Duration::hours(12));
+        assert_eq!(coffeepot.current_state(), PotState::Idle);
+        let expired = friday + Duration::hours(12);
+        assert_eq!(next(&rx), (PotState::Waiting, PotState::Idle, Cause::Timeout, expired));
+        clock.advance(Duration::days(3));
+        assert_eq!(coffeepot.current_state(), PotState::Idle);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn shifted_activation_keeps_its_id() {
+        let (coffeepot, clock, _rx) = pot(Coffeepot::builder());
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        let first = coffeepot.schedule(Brew::Default, start() + Duration::minutes(30), Cause::Terminal).unwrap().0;
+        let second = coffeepot.schedule(Brew::Default, start() + Duration::hours(5), Cause::Terminal).unwrap().0;
+        assert_eq!(coffeepot.shift_pending(Duration::minutes(10), Cause::Terminal).map(|(id, _)| id), Ok(first));
+        assert_eq!(coffeepot.shift_pending(Duration::hours(-1), Cause::Terminal), Err(CoffeepotError::InPast));
+        let ids: Vec<_> = coffeepot.scheduled().iter().map(|e| (e.id, e.activation.at)).collect();
+        assert_eq!(ids, vec![(first, start() + Duration::minutes(40)), (second, start() + Duration::hours(5))]);
+
+        clock.advance(Duration::minutes(39));
+        assert_eq!(coffeepot.current_state(), PotState::Waiting);
+        clock.advance(Duration::minutes(1));
+        assert_eq!(coffeepot.current_state(), PotState::Active);
+    }
+
+    #[test]
+    fn shifting_past_the_end_of_time_is_refused() {
+        let (coffeepot, _clock, _rx) = pot(Coffeepot::builder());
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        coffeepot.schedule(Brew::Default, start() + Duration::minutes(30), Cause::Terminal).unwrap();
+        let offset = Duration::minutes(1_000_000_000_000);
+        assert_eq!(coffeepot.shift_pending(offset, Cause::Terminal), Err(CoffeepotError::OutOfRange));
+        assert_eq!(coffeepot.current_state(), PotState::Waiting);
+    }
+
+    #[test]
+    fn ids_are_not_reused_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("coffeepot-test-ids-{}", std::process::id()));
+        let (coffeepot, _clock, _rx) = pot(Coffeepot::builder());
+        coffeepot.restore(StateFile::new(&path), RestorePolicy::NeverResumeActive);
+        coffeepot.toggle_ready(Cause::Terminal).unwrap();
+        let first = coffeepot.schedule(Brew::Default, start() + Duration::hours(1), Cause::Terminal).unwrap().0;
+        coffeepot.cancel_scheduled(first, Cause::Terminal).unwrap();
+        coffeepot.shutdown(std::time::Duration::from_secs(1));
+
+        let (coffeepot, _clock, rx) = pot(Coffeepot::builder());
+        coffeepot.restore(StateFile::new(&path), RestorePolicy::NeverResumeActive);
+        assert_eq!(next(&rx), (PotState::Idle, PotState::Ready, Cause::Startup, start()));
+        let second = coffeepot.schedule(Brew::Default, start() + Duration::hours(1), Cause::Terminal).unwrap().0;
+        assert_eq!(second, first + 1);
+        std::fs::remove_file(&path).ok();
+    }
+
+    /** What a pot restarted at `start()` comes back as from `saved` */
+    fn restored(name: &str, saved: &SavedState, policy: RestorePolicy) -> (PotState, Option<Duration>, Vec<u32>) {
+        let path = std::env::temp_dir().join(format!("coffeepot-test-{}-{}", name, std::process::id()));
+        StateFile::new(&path).save(saved).unwrap();
+        let (coffeepot, _clock, _rx) = pot(Coffeepot::builder());
+        coffeepot.restore(StateFile::new(&path), policy);
+        let ids = coffeepot.scheduled().iter().map(|e| e.id).collect();
+        let restored = (coffeepot.current_state(), coffeepot.remaining_active(), ids);
+        std::fs::remove_file(&path).ok();
+        restored
+    }
+
+    /**
+     * Saved in `state` with a 30 minute brew that started `started` ago, as the
+     * missed entry of the schedule if Waiting, and another brew due tomorrow
+     */
+    fn saved(state: PotState, started: Duration) -> SavedState {
+        let brew = |at, minutes| Activation {
+            at,
+            brew_time: Duration::minutes(minutes),
+            profile: None,
+        };
+        let at = start() - started;
+        let tomorrow = ScheduledActivation {
+            id: 2,
+            activation: brew(start() + Duration::days(1), 20),
+        };
+        match state {
+            PotState::Waiting => SavedState {
+                state,
+                activation: None,
+                schedule: vec![ScheduledActivation { id: 1, activation: brew(at, 30) }, tomorrow],
+                armed_at: Some(start() - Duration::hours(8)),
+                skipped: Vec::new(),
+                next_id: 3,
             },
-            PotState::Ready | PotState::Waiting => {
-                attrs.change_state(PotState::Idle);
+            _ => SavedState {
+                state,
+                activation: Some(brew(at, 30)),
+                schedule: vec![tomorrow],
+                armed_at: None,
+                skipped: Vec::new(),
+                next_id: 3,
             },
-            _ => warn!("ready toggle invalid in current state"),
         }
     }
 
-    pub fn toggle_active(&self) {
-        let mut attrs = self.props.lock().unwrap();
-        info!("toggling active");
-        match attrs.state {
-            PotState::Active => attrs.change_state(PotState::Idle),
-            _ => attrs.change_state(PotState::Active),
-        }
+    #[test]
+    fn restore_resumes_brews_still_in_their_window() {
+        let policy = RestorePolicy::Resume;
+        let missed = saved(PotState::Waiting, Duration::minutes(10));
+        let left = Some(Duration::minutes(20));
+        assert_eq!(restored("resume-missed", &missed, policy), (PotState::Active, left, vec![2]));
+        let over = saved(PotState::Waiting, Duration::hours(1));
+        assert_eq!(restored("resume-missed-over", &over, policy), (PotState::Waiting, None, vec![2]));
+        let active = saved(PotState::Active, Duration::minutes(10));
+        assert_eq!(restored("resume-active", &active, policy), (PotState::Active, left, vec![2]));
+        let over = saved(PotState::Active, Duration::hours(1));
+        assert_eq!(restored("resume-active-over", &over, policy), (PotState::Idle, None, vec![2]));
+    }
+
+    #[test]
+    fn restore_never_resumes_active() {
+        let policy = RestorePolicy::NeverResumeActive;
+        let missed = saved(PotState::Waiting, Duration::minutes(10));
+        assert_eq!(restored("never-missed", &missed, policy), (PotState::Waiting, None, vec![2]));
+        let active = saved(PotState::Active, Duration::minutes(10));
+        assert_eq!(restored("never-active", &active, policy), (PotState::Idle, None, vec![2]));
+        let over = saved(PotState::Active, Duration::hours(1));
+        assert_eq!(restored("never-active-over", &over, policy), (PotState::Idle, None, vec![2]));
+    }
+
+    #[test]
+    fn restore_falls_back_to_ready_without_the_schedule() {
+        let policy = RestorePolicy::FallBackToReady;
+        let missed = saved(PotState::Waiting, Duration::minutes(10));
+        assert_eq!(restored("ready-missed", &missed, policy), (PotState::Ready, None, vec![]));
+        let active = saved(PotState::Active, Duration::minutes(10));
+        assert_eq!(restored("ready-active", &active, policy), (PotState::Idle, None, vec![]));
+        let over = saved(PotState::Active, Duration::hours(1));
+        assert_eq!(restored("ready-active-over", &over, policy), (PotState::Idle, None, vec![]));
+    }
+
+    #[test]
+    fn stored_rules_longer_than_the_maximum_brew_are_dropped() {
+        let path = std::env::temp_dir().join(format!("coffeepot-test-rules-{}", std::process::id()));
+        std::fs::write(&path, "1 weekdays 06:15 30\n2 daily 07:00 600\n").unwrap();
+        let (coffeepot, _clock, _rx) = pot(Coffeepot::builder().max_brew_time(Duration::minutes(120)));
+        coffeepot.load_rules(RulesFile::new(&path));
+        let ids: Vec<_> = coffeepot.rules().iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![1]);
+        let id = coffeepot.add_rule("sat 09:00 20".parse().unwrap(), Cause::Terminal).unwrap().0;
+        assert_eq!(id, 3);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn activating_while_active_restarts_the_stages() {
+        let grind: BrewProfile = "ground 30 stages=grinder:60".parse().unwrap();
+        let (coffeepot, clock, rx) = pot(Coffeepot::builder().profiles(vec![grind]));
+        let stage = |t: Transition| (t.is_restart(), t.stage, t.cause);
+        coffeepot.activate(Brew::Profile("ground".to_string()), Cause::Terminal).unwrap();
+        next(&rx);
+        clock.advance(Duration::minutes(1));
+        let recv = || rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(stage(recv()), (false, Some(BREW_STAGE.to_string()), Cause::Stage));
+
+        coffeepot.activate(Brew::Profile("ground".to_string()), Cause::Terminal).unwrap();
+        assert_eq!(stage(recv()), (true, Some("grinder".to_string()), Cause::Terminal));
+        assert_eq!(coffeepot.remaining_active(), Some(Duration::minutes(30)));
     }
 }
@@ -0,0 +1,385 @@
+use crate::coffeepot::Brew;
+use crate::recurring::WeeklyRule;
+use chrono::prelude::*;
+use serde::Deserialize;
+use std::error::Error;
+
+/** How long `d` without an argument waits, in minutes */
+const DEFAULT_DELAY: i64 = 5;
+
+/** When a delayed activation should start */
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum When {
+    /** `<minutes>` from when the command is handled */
+    In(i64),
+    /** `@<rfc3339>` or `@<epoch seconds>`, unaffected by delivery delays */
+    At(DateTime<Utc>),
+}
+
+impl When {
+    /** The activation time, or `None` if it is too far out to be represented */
+    pub fn after(self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            When::In(minutes) => now.checked_add_signed(chrono::Duration::minutes(minutes)),
+            When::At(at) => Some(at),
+        }
+    }
+}
+
+impl std::str::FromStr for When {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let at = match s.strip_prefix('@') {
+            Some(at) => at,
+            None => return Ok(When::In(in_range(s.parse()?)?)),
+        };
+        if let Ok(epoch) = at.parse() {
+            return match Utc.timestamp_opt(epoch, 0) {
+                chrono::LocalResult::Single(at) => Ok(When::At(at)),
+                _ => Err(format!("invalid epoch time: {}", at).into()),
+            };
+        }
+        Ok(When::At(DateTime::parse_from_rfc3339(at)?.with_timezone(&Utc)))
+    }
+}
+
+/**
+ * Actions that can be injected over MQTT, one letter plus an argument. `a`,
+ * `d` and `s` take an optional brew time in minutes or brew profile name
+ * last, e.g. `s@<time> 20` or `d30 half`.
+ */
+#[derive(PartialEq, Clone, Debug)]
+pub enum Command {
+    /** `a`, `a<brew minutes>` or `a<profile>` */
+    Activate(Brew),
+    /** `i` */
+    Inactivate,
+    /** `d`, `d<minutes>` or `d@<time>`, replaces the whole schedule */
+    ActivateDelayed(When, Brew),
+    /** `s<minutes>` or `s@<time>`, adds an activation to the schedule */
+    Schedule(When, Brew),
+    /** `c<id>` */
+    CancelScheduled(u32),
+    /** `k`, skips the activation the pot is Waiting for */
+    CancelPending,
+    /** `z<minutes>`, moves the activation the pot is Waiting for, negative for earlier */
+    ShiftPending(i64),
+    /** `l`, publishes the schedule */
+    ListSchedule,
+    /** `w<days> <HH:MM> <brew minutes>`, adds a weekly rule */
+    AddRule(WeeklyRule),
+    /** `x<id>`, removes a weekly rule */
+    RemoveRule(u32),
+    /** `r`, publishes the weekly rules */
+    ListRules,
+    /** `f`, clears a latched fault */
+    ClearFault,
+}
+
+/**
+ * Refuses minutes that don't fit in a `chrono::Duration`, which would panic
+ * when the command is carried out.
+ */
+fn in_range(minutes: i64) -> Result<i64, Box<dyn Error>> {
+    let max = chrono::Duration::max_value().num_minutes();
+    if !(-max..=max).contains(&minutes) {
+        return Err(format!("{} minutes is out of range", minutes).into());
+    }
+    Ok(minutes)
+}
+
+fn argument(payload: &[u8]) -> Result<&str, Box<dyn Error>> {
+    Ok(std::str::from_utf8(&payload[1..])?.trim())
+}
+
+/** Profile names start with a letter, so anything else is a brew time */
+fn brew(argument: Option<&str>) -> Result<Brew, Box<dyn Error>> {
+    Ok(match argument {
+        None => Brew::Default,
+        Some(name) if name.starts_with(|c: char| c.is_ascii_alphabetic()) => Brew::Profile(name.to_string()),
+        Some(minutes) => Brew::For(chrono::Duration::minutes(in_range(minutes.parse()?)?)),
+    })
+}
+
+pub fn parse(payload: &[u8]) -> Result<Command, Box<dyn Error>> {
+    if payload.is_empty() {
+        return Err("payload empty!".into());
+    }
+    match payload[0] as char {
+        'a' => Ok(Command::Activate(brew(argument(payload)?.split_whitespace().next())?)),
+        'i' => Ok(Command::Inactivate),
+        'd' => {
+            let mut args = argument(payload)?.split_whitespace();
+            let when = match args.next() {
+                Some(when) => when.parse()?,
+                None => When::In(DEFAULT_DELAY),
+            };
+            Ok(Command::ActivateDelayed(when, brew(args.next())?))
+        }
+        's' => {
+            let mut args = argument(payload)?.split_whitespace();
+            let when = args.next().ok_or("missing activation time")?.parse()?;
+            Ok(Command::Schedule(when, brew(args.next())?))
+        }
+        'c' => Ok(Command::CancelScheduled(argument(payload)?.parse()?)),
+        'k' => Ok(Command::CancelPending),
+        'z' => Ok(Command::ShiftPending(in_range(argument(payload)?.parse()?)?)),
+        'l' => Ok(Command::ListSchedule),
+        'w' => Ok(Command::AddRule(argument(payload)?.parse()?)),
+        'x' => Ok(Command::RemoveRule(argument(payload)?.parse()?)),
+        'r' => Ok(Command::ListRules),
+        'f' => Ok(Command::ClearFault),
+        other => Err(format!("unexpected input: {}", other).into()),
+    }
+}
+
+/** Version of the JSON commands, bumped whenever an existing command changes meaning */
+const PROTOCOL_VERSION: u64 = 1;
+
+/** A JSON command along with the id the sender gave it, if any */
+#[derive(PartialEq, Clone, Debug)]
+pub struct Request {
+    pub id: Option<String>,
+    pub command: Command,
+}
+
+/**
+ * Commands in version 1 of the JSON protocol, named by `cmd`. Times are
+ * RFC 3339 and durations in minutes, and fields that don't belong to the
+ * command are rejected. Commands without fields are empty structs, as unit
+ * variants would let anything through.
+ */
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+enum JsonCommand {
+    Activate {
+        brew_minutes: Option<i64>,
+        profile: Option<String>,
+    },
+    Inactivate {},
+    ActivateDelayed {
+        in_minutes: Option<i64>,
+        at: Option<DateTime<Utc>>,
+        brew_minutes: Option<i64>,
+        profile: Option<String>,
+    },
+    Schedule {
+        in_minutes: Option<i64>,
+        at: Option<DateTime<Utc>>,
+        brew_minutes: Option<i64>,
+        profile: Option<String>,
+    },
+    CancelScheduled {
+        schedule_id: u32,
+    },
+    CancelPending {},
+    ShiftPending {
+        minutes: i64,
+    },
+    ListSchedule {},
+    AddRule {
+        days: String,
+        time: String,
+        brew_minutes: i64,
+    },
+    RemoveRule {
+        rule_id: u32,
+    },
+    ListRules {},
+    ClearFault {},
+}
+
+fn json_brew(minutes: Option<i64>, profile: Option<String>) -> Result<Brew, Box<dyn Error>> {
+    Ok(match (minutes, profile) {
+        (None, None) => Brew::Default,
+        (Some(minutes), None) => Brew::For(chrono::Duration::minutes(in_range(minutes)?)),
+        (None, Some(profile)) => Brew::Profile(profile),
+        (Some(_), Some(_)) => return Err("only one of brew_minutes and profile can be given".into()),
+    })
+}
+
+fn json_when(in_minutes: Option<i64>, at: Option<DateTime<Utc>>) -> Result<Option<When>, Box<dyn Error>> {
+    Ok(match (in_minutes, at) {
+        (None, None) => None,
+        (Some(minutes), None) => Some(When::In(in_range(minutes)?)),
+        (None, Some(at)) => Some(When::At(at)),
+        (Some(_), Some(_)) => return Err("only one of in_minutes and at can be given".into()),
+    })
+}
+
+impl JsonCommand {
+    fn into_command(self) -> Result<Command, Box<dyn Error>> {
+        Ok(match self {
+            JsonCommand::Activate { brew_minutes, profile } => Command::Activate(json_brew(brew_minutes, profile)?),
+            JsonCommand::Inactivate {} => Command::Inactivate,
+            JsonCommand::ActivateDelayed { in_minutes, at, brew_minutes, profile } => Command::ActivateDelayed(
+                json_when(in_minutes, at)?.unwrap_or(When::In(DEFAULT_DELAY)),
+                json_brew(brew_minutes, profile)?,
+            ),
+            JsonCommand::Schedule { in_minutes, at, brew_minutes, profile } => Command::Schedule(
+                json_when(in_minutes, at)?.ok_or("missing activation time, give in_minutes or at")?,
+                json_brew(brew_minutes, profile)?,
+            ),
+            JsonCommand::CancelScheduled { schedule_id } => Command::CancelScheduled(schedule_id),
+            JsonCommand::CancelPending {} => Command::CancelPending,
+            JsonCommand::ShiftPending { minutes } => Command::ShiftPending(in_range(minutes)?),
+            JsonCommand::ListSchedule {} => Command::ListSchedule,
+            JsonCommand::AddRule { days, time, brew_minutes } => {
+                Command::AddRule(format!("{} {} {}", days, time, brew_minutes).parse()?)
+            }
+            JsonCommand::RemoveRule { rule_id } => Command::RemoveRule(rule_id),
+            JsonCommand::ListRules {} => Command::ListRules,
+            JsonCommand::ClearFault {} => Command::ClearFault,
+        })
+    }
+}
+
+/**
+ * Parses `{"v": 1, "id": <optional string>, "cmd": <command>, ...}`, e.g.
+ * `{"v": 1, "cmd": "schedule", "at": "2020-01-01T06:05:00+01:00", "profile": "half"}`.
+ * The version is checked first, so a newer sender gets a clear error rather
+ * than a command misread.
+ */
+pub fn parse_json(payload: &[u8]) -> Result<Request, Box<dyn Error>> {
+    let mut json: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(payload)?;
+    match json.remove("v").map(|v| v.as_u64()) {
+        Some(Some(PROTOCOL_VERSION)) => (),
+        Some(Some(v)) => return Err(format!("unsupported protocol version {}, expected {}", v, PROTOCOL_VERSION).into()),
+        Some(None) => return Err("v must be a protocol version number".into()),
+        None => return Err("protocol version v missing".into()),
+    }
+    let id = match json.remove("id") {
+        Some(serde_json::Value::String(id)) => Some(id),
+        Some(_) => return Err("id must be a string".into()),
+        None => None,
+    };
+    let command = serde_json::from_value::<JsonCommand>(json.into())?.into_command()?;
+    Ok(Request { id, command })
+}
+
+/** The id of a JSON request that couldn't be parsed, if it has one */
+pub fn request_id(payload: &[u8]) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    json.get("id")?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn json(payload: &str) -> Result<Request, String> {
+        parse_json(payload.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn command(payload: &str) -> Command {
+        json(payload).unwrap().command
+    }
+
+    #[test]
+    fn version_must_be_1() {
+        assert_eq!(json(r#"{"cmd": "inactivate"}"#), Err("protocol version v missing".to_string()));
+        assert_eq!(
+            json(r#"{"v": 2, "cmd": "inactivate"}"#),
+            Err("unsupported protocol version 2, expected 1".to_string())
+        );
+        assert!(json(r#"{"v": "1", "cmd": "inactivate"}"#).is_err());
+        assert!(json(r#"{"v": -1, "cmd": "inactivate"}"#).is_err());
+        assert_eq!(command(r#"{"v": 1, "cmd": "inactivate"}"#), Command::Inactivate);
+    }
+
+    #[test]
+    fn unknown_commands_and_fields_are_rejected() {
+        assert!(json(r#"{"v": 1, "cmd": "brew"}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "activate", "brew_minute": 20}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "inactivate", "now": true}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "cancel_scheduled", "rule_id": 1}"#).is_err());
+        assert!(json(r#"{"v": 1}"#).is_err());
+    }
+
+    #[test]
+    fn only_one_activation_time_and_brew() {
+        assert!(json(r#"{"v": 1, "cmd": "schedule", "in_minutes": 5, "at": "2020-01-01T06:05:00Z"}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "activate_delayed", "in_minutes": 5, "at": "2020-01-01T06:05:00Z"}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "activate", "brew_minutes": 20, "profile": "half"}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "schedule", "profile": "half"}"#).is_err());
+        let at = Utc.ymd(2020, 1, 1).and_hms(5, 5, 0);
+        assert_eq!(
+            json(r#"{"v": 1, "id": "morning", "cmd": "schedule", "at": "2020-01-01T06:05:00+01:00", "profile": "half"}"#),
+            Ok(Request {
+                id: Some("morning".to_string()),
+                command: Command::Schedule(When::At(at), Brew::Profile("half".to_string())),
+            })
+        );
+        assert_eq!(
+            command(r#"{"v": 1, "cmd": "activate_delayed", "brew_minutes": 20}"#),
+            Command::ActivateDelayed(When::In(DEFAULT_DELAY), Brew::For(Duration::minutes(20)))
+        );
+    }
+
+    #[test]
+    fn id_is_found_when_the_request_is_rejected() {
+        let payload = br#"{"v": 2, "id": "morning", "cmd": "activate"}"#;
+        assert!(parse_json(payload).is_err());
+        assert_eq!(request_id(payload), Some("morning".to_string()));
+        let payload = br#"{"v": 1, "id": "morning", "cmd": "activate", "brew_minutes": "lots"}"#;
+        assert!(parse_json(payload).is_err());
+        assert_eq!(request_id(payload), Some("morning".to_string()));
+        assert!(json(r#"{"v": 1, "id": 7, "cmd": "activate"}"#).is_err());
+        assert_eq!(request_id(br#"{"v": 1, "id": 7, "cmd": "activate"}"#), None);
+        assert_eq!(request_id(b"{\"id\": \"morning\""), None);
+    }
+
+    #[test]
+    fn letters_still_work() {
+        let letter = |payload: &str| parse(payload.as_bytes()).unwrap();
+        assert_eq!(letter("a"), Command::Activate(Brew::Default));
+        assert_eq!(letter("a20"), Command::Activate(Brew::For(Duration::minutes(20))));
+        assert_eq!(letter("ahalf"), Command::Activate(Brew::Profile("half".to_string())));
+        assert_eq!(letter("i"), Command::Inactivate);
+        assert_eq!(letter("d"), Command::ActivateDelayed(When::In(DEFAULT_DELAY), Brew::Default));
+        assert_eq!(letter("d30"), Command::ActivateDelayed(When::In(30), Brew::Default));
+        assert_eq!(
+            letter("d30 half"),
+            Command::ActivateDelayed(When::In(30), Brew::Profile("half".to_string()))
+        );
+        let at = Utc.timestamp(1577858700, 0);
+        assert_eq!(
+            letter("s@1577858700 20"),
+            Command::Schedule(When::At(at), Brew::For(Duration::minutes(20)))
+        );
+        assert!(parse(b"").is_err());
+        assert!(parse(b"dsoon").is_err());
+        assert!(parse(b"q").is_err());
+    }
+
+    #[test]
+    fn rules_take_exactly_days_time_and_minutes() {
+        let rule = |days: &str, time: &str| {
+            json(&format!(
+                r#"{{"v": 1, "cmd": "add_rule", "days": "{}", "time": "{}", "brew_minutes": 20}}"#,
+                days, time
+            ))
+        };
+        assert!(matches!(rule("weekdays", "06:15").unwrap().command, Command::AddRule(_)));
+        assert!(rule("mon 06:00 20", "07:00").is_err());
+        assert!(rule("mon", "07:00 30").is_err());
+        assert!(parse(b"wmon 06:00 20").is_ok());
+        assert!(parse(b"wmon 06:00 20 junk").is_err());
+    }
+
+    #[test]
+    fn minutes_too_large_for_a_duration_are_rejected() {
+        assert!(parse(b"a9999999999999999").is_err());
+        assert!(parse(b"d9999999999999999").is_err());
+        assert!(parse(b"z-9223372036854775808").is_err());
+        assert!(json(r#"{"v": 1, "cmd": "activate", "brew_minutes": 9999999999999999}"#).is_err());
+        assert!(json(r#"{"v": 1, "cmd": "shift_pending", "minutes": 9999999999999999}"#).is_err());
+        let far = parse(b"d1000000000000").unwrap();
+        match far {
+            Command::ActivateDelayed(when, _) => assert_eq!(when.after(Utc::now()), None),
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,722 @@
+use crate::persist::RestorePolicy;
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/** Where the config file is looked for unless `--config` or `COFFEEPOT_CONFIG` says otherwise */
+#[cfg(target_arch = "arm")]
+const DEFAULT_PATH: &str = "/etc/coffeepot.toml";
+#[cfg(not(target_arch = "arm"))]
+const DEFAULT_PATH: &str = "coffeepot.toml";
+
+/** GPIO pins used by the LED PWM channels, which can't be moved */
+const PWM_PINS: &[u8] = &[18, 19];
+/** Highest BCM pin number on the header */
+const MAX_PIN: u8 = 27;
+
+/** A century, so that adding any duration to now still gives a representable time */
+const MAX_HOURS: i64 = 100 * 365 * 24;
+const MAX_MINUTES: i64 = MAX_HOURS * 60;
+
+/** System CA bundle, used for TLS when no other is given */
+const SYSTEM_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    /** 8883 with TLS, 1883 without, if not given */
+    pub port: Option<u16>,
+    pub client_id: String,
+    /** Every topic is published and subscribed to below this, e.g. `<prefix>/actions` */
+    pub topic_prefix: String,
+    pub tls: bool,
+    /** PEM bundle of the CAs to trust, the system bundle if not given */
+    pub ca_file: Option<String>,
+    /** PEM client certificate and RSA key for mutual TLS */
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+    pub username: Option<String>,
+    pub username_file: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        let arm = cfg!(target_arch = "arm");
+        MqttConfig {
+            host: if arm { "bosch.hnrklssn.se" } else { "test.mosquitto.org" }.to_string(),
+            port: None,
+            client_id: "coffeepot".to_string(),
+            topic_prefix: "coffeepot".to_string(),
+            tls: arm,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            username: None,
+            username_file: None,
+            password: None,
+            password_file: None,
+        }
+    }
+}
+
+/** PEM contents for an encrypted connection */
+pub struct Tls {
+    pub ca: Vec<u8>,
+    /** Client certificate and key */
+    pub client_auth: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/**
+ * Relative paths are looked up in `$CREDENTIALS_DIRECTORY` if set, so files
+ * passed with systemd's `LoadCredential=` can be given by name.
+ */
+fn credential_path(path: &str) -> PathBuf {
+    match env::var_os("CREDENTIALS_DIRECTORY") {
+        Some(dir) if Path::new(path).is_relative() => Path::new(&dir).join(path),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn read_credential(path: &str) -> Result<Vec<u8>, String> {
+    let path = credential_path(path);
+    fs::read(&path).map_err(|e| format!("could not read {}: {}", path.display(), e))
+}
+
+/** Reads a secret that is alone in a file, ignoring the trailing newline */
+fn read_secret(path: &str) -> Result<String, String> {
+    let secret = String::from_utf8(read_credential(path)?).map_err(|_| format!("{} is not UTF-8", path))?;
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/** Picks whichever of a value and a file holding it was given */
+fn value_or_file(value: &Option<String>, file: &Option<String>) -> Result<Option<String>, String> {
+    match (value, file) {
+        (Some(value), _) => Ok(Some(value.clone())),
+        (None, Some(file)) => read_secret(file).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+fn contains_pem(pem: &[u8], label: &str) -> bool {
+    String::from_utf8_lossy(pem).contains(&format!("-----BEGIN {}-----", label))
+}
+
+impl MqttConfig {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.tls { 8883 } else { 1883 })
+    }
+
+    /** Username and password, read from their files if given that way */
+    pub fn credentials(&self) -> Result<Option<(String, String)>, String> {
+        let username = value_or_file(&self.username, &self.username_file)?;
+        let password = value_or_file(&self.password, &self.password_file)?;
+        match (username, password) {
+            (Some(username), Some(password)) => Ok(Some((username, password))),
+            (None, None) => Ok(None),
+            _ => Err("mqtt username and password must be given together".to_string()),
+        }
+    }
+
+    /** The CA bundle and any client certificate, or `None` for plain TCP */
+    pub fn tls(&self) -> Result<Option<Tls>, String> {
+        if !self.tls {
+            return Ok(None);
+        }
+        let ca_file = self.ca_file.as_deref().unwrap_or(SYSTEM_CA_FILE);
+        let ca = read_credential(ca_file)?;
+        if !contains_pem(&ca, "CERTIFICATE") {
+            return Err(format!("no PEM certificates in {}", ca_file));
+        }
+        let client_auth = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let cert = read_credential(cert_file)?;
+                if !contains_pem(&cert, "CERTIFICATE") {
+                    return Err(format!("no PEM certificate in {}", cert_file));
+                }
+                let key = read_credential(key_file)?;
+                // the only kind of key the TLS library takes
+                if !contains_pem(&key, "RSA PRIVATE KEY") {
+                    return Err(format!(
+                        "{} must be an RSA key in PKCS#1 PEM (BEGIN RSA PRIVATE KEY), convert it with `openssl rsa -traditional`",
+                        key_file
+                    ));
+                }
+                Some((cert, key))
+            }
+            (None, None) => None,
+            _ => return Err("mqtt.client_cert_file and mqtt.client_key_file must be given together".to_string()),
+        };
+        Ok(Some(Tls { ca, client_auth }))
+    }
+}
+
+/** BCM numbers of the GPIO pins for the buttons and relays */
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pins {
+    pub ready_button: u8,
+    pub power_button: u8,
+    pub relay: u8,
+    pub grinder: u8,
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Pins {
+            ready_button: 17,
+            power_button: 22,
+            relay: 27,
+            grinder: 23,
+        }
+    }
+}
+
+/** How long brews, and the states around them, may last */
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrewConfig {
+    /**
+     * Minutes to brew for commands that don't give a brew time, 90 if not
+     * given, or 5 seconds in the x86 demo
+     */
+    pub minutes: Option<i64>,
+    /** Longer brews are refused */
+    pub max_minutes: i64,
+    /** How long to keep warm after a timed brew, 0 skips it */
+    pub keep_warm_minutes: i64,
+    /** How many hours the pot may stay armed without brewing, for ever if not given */
+    pub ready_expiry_hours: Option<i64>,
+    /** How long the watchdog lets the relay stay on before latching a fault */
+    pub max_on_minutes: i64,
+}
+
+impl Default for BrewConfig {
+    fn default() -> Self {
+        BrewConfig {
+            minutes: None,
+            max_minutes: 120,
+            keep_warm_minutes: 0,
+            ready_expiry_hours: None,
+            max_on_minutes: 180,
+        }
+    }
+}
+
+impl BrewConfig {
+    pub fn max_on_time(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.max_on_minutes as u64 * 60)
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let max = MAX_MINUTES;
+        let minutes = [
+            ("brew.minutes", self.minutes),
+            ("brew.max_minutes", Some(self.max_minutes)),
+            ("brew.max_on_minutes", Some(self.max_on_minutes)),
+        ];
+        for (name, value) in minutes.iter() {
+            match value {
+                Some(value) if *value <= 0 || *value > max => {
+                    problems.push(format!("{} must be between 1 and {}, got {}", name, max, value))
+                }
+                _ => (),
+            }
+        }
+        if self.keep_warm_minutes < 0 || self.keep_warm_minutes > max {
+            problems.push(format!("brew.keep_warm_minutes must be between 0 and {}, got {}", max, self.keep_warm_minutes));
+        }
+        match self.ready_expiry_hours {
+            Some(hours) if hours <= 0 || hours > MAX_HOURS => problems.push(format!(
+                "brew.ready_expiry_hours must be between 1 and {}, got {}",
+                MAX_HOURS, hours
+            )),
+            _ => (),
+        }
+        // the relay stays on from the start of a brew to the end of keeping it warm
+        let longest = self.max_minutes.saturating_add(self.keep_warm_minutes);
+        if self.max_on_minutes > 0 && self.max_on_minutes <= longest {
+            problems.push(format!(
+                "brew.max_on_minutes is {}, the watchdog would trip during a {} minute brew kept warm for {} minutes",
+                self.max_on_minutes, self.max_minutes, self.keep_warm_minutes
+            ));
+        }
+        match self.minutes {
+            Some(minutes) if minutes > self.max_minutes => problems.push(format!(
+                "brew.minutes is {}, longer than brew.max_minutes {}",
+                minutes, self.max_minutes
+            )),
+            _ => (),
+        }
+        problems
+    }
+}
+
+/**
+ * Everything that differs between kitchens. Read from a TOML file, then
+ * overridden by environment variables, then by command line flags. Anything
+ * left out keeps its default.
+ */
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /** Log file for release builds on the Pi */
+    pub log: String,
+    /** Default directory of the state, rules and profiles files */
+    pub data_dir: String,
+    /** `<data_dir>/state` if not given */
+    pub state_file: Option<String>,
+    /** `<data_dir>/rules` if not given */
+    pub rules_file: Option<String>,
+    /** `<data_dir>/profiles` if not given */
+    pub profiles_file: Option<String>,
+    /** What to bring back from the state file on startup */
+    pub restore: RestorePolicy,
+    pub brew: BrewConfig,
+    pub mqtt: MqttConfig,
+    pub pins: Pins,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let arm = cfg!(target_arch = "arm");
+        Config {
+            log: "/var/log/coffeepot.log".to_string(),
+            data_dir: if arm { "/var/lib/coffeepot" } else { "." }.to_string(),
+            state_file: None,
+            rules_file: None,
+            profiles_file: None,
+            restore: RestorePolicy::NeverResumeActive,
+            brew: BrewConfig::default(),
+            mqtt: MqttConfig::default(),
+            pins: Pins::default(),
+        }
+    }
+}
+
+/** Every problem found with the config, so they can all be fixed in one go */
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+// main prints errors with Debug
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<String> for ConfigError {
+    fn from(problem: String) -> Self {
+        ConfigError(vec![problem])
+    }
+}
+
+type Setter = fn(&mut Config, &str) -> Result<(), String>;
+
+/** Settings that can be overridden by a command line flag or an environment variable */
+const OVERRIDES: &[(&str, &str, Setter)] = &[
+    ("--host", "COFFEEPOT_HOST", |c, v| {
+        c.mqtt.host = v.to_string();
+        Ok(())
+    }),
+    ("--port", "COFFEEPOT_PORT", |c, v| {
+        c.mqtt.port = Some(v.parse().map_err(|e| format!("invalid port {}: {}", v, e))?);
+        Ok(())
+    }),
+    ("--tls", "COFFEEPOT_TLS", |c, v| {
+        c.mqtt.tls = v.parse().map_err(|_| format!("expected true or false, got {}", v))?;
+        Ok(())
+    }),
+    ("--ca-file", "COFFEEPOT_CA_FILE", |c, v| {
+        c.mqtt.ca_file = Some(v.to_string());
+        Ok(())
+    }),
+    ("--client-cert", "COFFEEPOT_CLIENT_CERT", |c, v| {
+        c.mqtt.client_cert_file = Some(v.to_string());
+        Ok(())
+    }),
+    ("--client-key", "COFFEEPOT_CLIENT_KEY", |c, v| {
+        c.mqtt.client_key_file = Some(v.to_string());
+        Ok(())
+    }),
+    ("--client-id", "COFFEEPOT_CLIENT_ID", |c, v| {
+        c.mqtt.client_id = v.to_string();
+        Ok(())
+    }),
+    ("--topic-prefix", "COFFEEPOT_TOPIC_PREFIX", |c, v| {
+        c.mqtt.topic_prefix = v.to_string();
+        Ok(())
+    }),
+    ("--user", "COFFEEPOT_USER", |c, v| {
+        c.mqtt.username = Some(v.to_string());
+        c.mqtt.username_file = None;
+        Ok(())
+    }),
+    ("--user-file", "COFFEEPOT_USER_FILE", |c, v| {
+        c.mqtt.username_file = Some(v.to_string());
+        c.mqtt.username = None;
+        Ok(())
+    }),
+    ("--pass", "COFFEEPOT_PASS", |c, v| {
+        c.mqtt.password = Some(v.to_string());
+        c.mqtt.password_file = None;
+        Ok(())
+    }),
+    ("--pass-file", "COFFEEPOT_PASS_FILE", |c, v| {
+        c.mqtt.password_file = Some(v.to_string());
+        c.mqtt.password = None;
+        Ok(())
+    }),
+    ("--log", "COFFEEPOT_LOG", |c, v| {
+        c.log = v.to_string();
+        Ok(())
+    }),
+    ("--data-dir", "COFFEEPOT_DATA_DIR", |c, v| {
+        c.data_dir = v.to_string();
+        Ok(())
+    }),
+    ("--state-file", "COFFEEPOT_STATE_FILE", |c, v| {
+        c.state_file = Some(v.to_string());
+        Ok(())
+    }),
+    ("--rules-file", "COFFEEPOT_RULES_FILE", |c, v| {
+        c.rules_file = Some(v.to_string());
+        Ok(())
+    }),
+    ("--profiles-file", "COFFEEPOT_PROFILES_FILE", |c, v| {
+        c.profiles_file = Some(v.to_string());
+        Ok(())
+    }),
+    ("--restore", "COFFEEPOT_RESTORE", |c, v| {
+        c.restore = v.parse()?;
+        Ok(())
+    }),
+    ("--brew-time", "COFFEEPOT_BREW_TIME", |c, v| {
+        c.brew.minutes = Some(number(v)?);
+        Ok(())
+    }),
+    ("--max-brew", "COFFEEPOT_MAX_BREW", |c, v| {
+        c.brew.max_minutes = number(v)?;
+        Ok(())
+    }),
+    ("--keep-warm", "COFFEEPOT_KEEP_WARM", |c, v| {
+        c.brew.keep_warm_minutes = number(v)?;
+        Ok(())
+    }),
+    ("--ready-expiry", "COFFEEPOT_READY_EXPIRY", |c, v| {
+        c.brew.ready_expiry_hours = Some(number(v)?);
+        Ok(())
+    }),
+    ("--max-on", "COFFEEPOT_MAX_ON", |c, v| {
+        c.brew.max_on_minutes = number(v)?;
+        Ok(())
+    }),
+];
+
+fn number(value: &str) -> Result<i64, String> {
+    value.parse().map_err(|e| format!("invalid number {}: {}", value, e))
+}
+
+/** Splits `--flag value` and `--flag=value` pairs */
+fn flags(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("unexpected argument {}", arg));
+        }
+        let (flag, value) = match arg.find('=') {
+            Some(i) => (arg[..i].to_string(), arg[i + 1..].to_string()),
+            None => match args.next() {
+                Some(value) => (arg.clone(), value.clone()),
+                None => return Err(format!("{} needs a value", arg)),
+            },
+        };
+        flags.push((flag, value));
+    }
+    Ok(flags)
+}
+
+impl Config {
+    fn data_file(&self, file: &Option<String>, name: &str) -> String {
+        file.clone().unwrap_or_else(|| format!("{}/{}", self.data_dir, name))
+    }
+
+    pub fn state_file(&self) -> String {
+        self.data_file(&self.state_file, "state")
+    }
+
+    pub fn rules_file(&self) -> String {
+        self.data_file(&self.rules_file, "rules")
+    }
+
+    pub fn profiles_file(&self) -> String {
+        self.data_file(&self.profiles_file, "profiles")
+    }
+
+    /**
+     * Reads the file given by `--config` or `COFFEEPOT_CONFIG`, falling back
+     * to the defaults if there is nothing at the default path, applies the
+     * overrides and validates the result.
+     */
+    pub fn load(args: &[String]) -> Result<Config, ConfigError> {
+        let flags = flags(args)?;
+        let explicit_path = flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == "--config")
+            .map(|(_, path)| path.clone())
+            .or_else(|| env::var("COFFEEPOT_CONFIG").ok());
+        let path = explicit_path.clone().unwrap_or_else(|| DEFAULT_PATH.to_string());
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && explicit_path.is_none() => Config::default(),
+            Err(e) => return Err(format!("could not read {}: {}", path, e).into()),
+        };
+        let mut problems = Vec::new();
+        for (_, var, set) in OVERRIDES {
+            if let Ok(value) = env::var(var) {
+                set(&mut config, &value).unwrap_or_else(|e| problems.push(format!("{}: {}", var, e)));
+            }
+        }
+        for (flag, value) in &flags {
+            match OVERRIDES.iter().find(|(name, _, _)| name == flag) {
+                Some((_, _, set)) => set(&mut config, value).unwrap_or_else(|e| problems.push(format!("{}: {}", flag, e))),
+                None if flag == "--config" => (),
+                None => problems.push(format!("unknown flag {}", flag)),
+            }
+        }
+        problems.extend(config.problems());
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mqtt = &self.mqtt;
+        if mqtt.host.is_empty() || mqtt.host.contains(char::is_whitespace) {
+            problems.push(format!("mqtt.host is not a host name: {:?}", mqtt.host));
+        }
+        if mqtt.port() == 0 {
+            problems.push("mqtt.port must not be 0".to_string());
+        }
+        // certificates are only checked against host names
+        if mqtt.tls && mqtt.host.parse::<IpAddr>().is_ok() {
+            problems.push(format!("mqtt.host must be a host name rather than an address for TLS: {}", mqtt.host));
+        }
+        if !mqtt.tls && (mqtt.ca_file.is_some() || mqtt.client_cert_file.is_some() || mqtt.client_key_file.is_some()) {
+            problems.push("mqtt.ca_file and client certificates need mqtt.tls = true".to_string());
+        }
+        if mqtt.username.is_some() && mqtt.username_file.is_some() {
+            problems.push("only one of mqtt.username and mqtt.username_file can be given".to_string());
+        }
+        if mqtt.password.is_some() && mqtt.password_file.is_some() {
+            problems.push("only one of mqtt.password and mqtt.password_file can be given".to_string());
+        }
+        problems.extend(mqtt.credentials().err());
+        problems.extend(mqtt.tls().err());
+        if mqtt.client_id.is_empty() {
+            problems.push("mqtt.client_id must not be empty".to_string());
+        }
+        let prefix = &mqtt.topic_prefix;
+        if prefix.is_empty() || prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(&['+', '#'][..]) {
+            problems.push(format!(
+                "mqtt.topic_prefix must be a topic without wildcards or a leading or trailing /: {:?}",
+                prefix
+            ));
+        }
+        let pins = [
+            ("ready_button", self.pins.ready_button),
+            ("power_button", self.pins.power_button),
+            ("relay", self.pins.relay),
+            ("grinder", self.pins.grinder),
+        ];
+        for (i, (name, pin)) in pins.iter().enumerate() {
+            if *pin > MAX_PIN {
+                problems.push(format!("pins.{} is {}, there is no such GPIO pin", name, pin));
+            } else if PWM_PINS.contains(pin) {
+                problems.push(format!("pins.{} is {}, which the LEDs use", name, pin));
+            }
+            if let Some((other, _)) = pins[..i].iter().find(|(_, p)| p == pin) {
+                problems.push(format!("pins.{} and pins.{} are both {}", other, name, pin));
+            }
+        }
+        if self.log.is_empty() {
+            problems.push("log must not be empty".to_string());
+        }
+        if self.data_dir.is_empty() {
+            problems.push("data_dir must not be empty".to_string());
+        }
+        let files = [
+            ("state_file", &self.state_file),
+            ("rules_file", &self.rules_file),
+            ("profiles_file", &self.profiles_file),
+        ];
+        for (name, file) in files.iter() {
+            if file.as_deref() == Some("") {
+                problems.push(format!("{} must not be empty", name));
+            }
+        }
+        problems.extend(self.brew.problems());
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::sync::Mutex;
+
+    /** Held by tests that load, so they don't see each other's environment */
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /** A config file with `contents` that no other test uses */
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("coffeepot-config-{}-{}.toml", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(path: &Path, extra: &[&str]) -> Result<Config, Vec<String>> {
+        let mut all = args(&["--config", path.to_str().unwrap()]);
+        all.extend(args(extra));
+        Config::load(&all).map_err(|e| e.0)
+    }
+
+    #[test]
+    fn flags_take_values_after_a_space_or_an_equals_sign() {
+        let pairs = |list: &[&str]| flags(&args(list));
+        let pair = |flag: &str, value: &str| (flag.to_string(), value.to_string());
+        assert_eq!(pairs(&[]), Ok(vec![]));
+        assert_eq!(
+            pairs(&["--host", "pot.local", "--port=8883", "--pass=a=b", "--user="]),
+            Ok(vec![
+                pair("--host", "pot.local"),
+                pair("--port", "8883"),
+                pair("--pass", "a=b"),
+                pair("--user", ""),
+            ])
+        );
+        // a value may look like a flag
+        assert_eq!(pairs(&["--pass", "--secret"]), Ok(vec![pair("--pass", "--secret")]));
+        assert_eq!(pairs(&["--host"]), Err("--host needs a value".to_string()));
+        assert_eq!(pairs(&["pot.local"]), Err("unexpected argument pot.local".to_string()));
+        assert_eq!(pairs(&["-h", "pot.local"]), Err("unexpected argument -h".to_string()));
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let path = config_file(
+            "precedence",
+            "[mqtt]\nhost = \"file.local\"\nclient_id = \"file\"\ntopic_prefix = \"file\"\n\
+             [brew]\nmax_minutes = 30\nkeep_warm_minutes = 10\n",
+        );
+        env::set_var("COFFEEPOT_HOST", "env.local");
+        env::set_var("COFFEEPOT_CLIENT_ID", "env");
+        let config = load(&path, &["--client-id=flag", "--keep-warm", "20", "--keep-warm", "25"]);
+        env::remove_var("COFFEEPOT_HOST");
+        env::remove_var("COFFEEPOT_CLIENT_ID");
+        fs::remove_file(&path).ok();
+
+        let config = config.unwrap();
+        assert_eq!(config.mqtt.topic_prefix, "file");
+        assert_eq!(config.brew.max_minutes, 30);
+        assert_eq!(config.mqtt.host, "env.local");
+        assert_eq!(config.mqtt.client_id, "flag");
+        // the last of a repeated flag wins
+        assert_eq!(config.brew.keep_warm_minutes, 25);
+        // left out of the file
+        assert_eq!(config.brew.max_on_minutes, 180);
+        assert_eq!(config.state_file(), format!("{}/state", Config::default().data_dir));
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let path = config_file("problems", "[brew]\nkeep_warm_minutes = -1\n");
+        let problems = load(&path, &["--port=x", "--bogus=1", "--restore", "never", "--client-id="]);
+        fs::remove_file(&path).ok();
+
+        let problems = problems.unwrap_err();
+        let expected = [
+            "--port: invalid port x",
+            "unknown flag --bogus",
+            "--restore: unknown restore policy: never",
+            "mqtt.client_id must not be empty",
+            "brew.keep_warm_minutes must be between 0 and",
+        ];
+        assert_eq!(problems.len(), expected.len(), "{:?}", problems);
+        for (problem, expected) in problems.iter().zip(expected.iter()) {
+            assert!(problem.starts_with(expected), "{} is not {}", problem, expected);
+        }
+    }
+
+    #[test]
+    fn an_explicit_config_file_must_exist() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let path = env::temp_dir().join(format!("coffeepot-config-{}-missing.toml", process::id()));
+        let problems = load(&path, &[]).unwrap_err();
+        assert!(problems[0].starts_with("could not read"), "{:?}", problems);
+        let path = config_file("unknown", "[brew]\nminutes = 5\nseconds = 5\n");
+        assert!(load(&path, &[]).is_err());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn pins_must_be_free_and_distinct() {
+        let mut config = Config::default();
+        assert!(config.problems().is_empty(), "{:?}", config.problems());
+        config.pins.relay = config.pins.ready_button;
+        config.pins.grinder = 18;
+        config.pins.power_button = 40;
+        assert_eq!(
+            config.problems(),
+            vec![
+                "pins.power_button is 40, there is no such GPIO pin".to_string(),
+                "pins.ready_button and pins.relay are both 17".to_string(),
+                "pins.grinder is 18, which the LEDs use".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn brew_times_are_bounded() {
+        let mut brew = BrewConfig::default();
+        assert!(brew.problems().is_empty());
+        brew.ready_expiry_hours = Some(1_000_000_000);
+        brew.keep_warm_minutes = MAX_MINUTES + 1;
+        brew.minutes = Some(0);
+        let problems = brew.problems();
+        assert!(problems.iter().any(|p| p.starts_with("brew.ready_expiry_hours")), "{:?}", problems);
+        assert!(problems.iter().any(|p| p.starts_with("brew.keep_warm_minutes")), "{:?}", problems);
+        assert!(problems.iter().any(|p| p.starts_with("brew.minutes")), "{:?}", problems);
+    }
+}
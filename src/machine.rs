@@ -0,0 +1,240 @@
+use crate::coffeepot::PotState;
+use crate::coffeepot::PotState::*;
+use std::fmt::Write;
+
+/** Everything that can make the state machine move */
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Input {
+    ToggleReady,
+    TogglePower,
+    Activate,
+    Inactivate,
+    /** A delayed activation was added, which needs the pot to be armed */
+    Schedule,
+    /** A delayed activation or weekly rule was removed, or a rule added */
+    ScheduleChanged,
+    /** The activation the pot is Waiting for was dropped */
+    CancelPending,
+    /** The activation the pot is Waiting for was moved */
+    ShiftPending,
+    /** The next delayed activation or weekly rule fell due */
+    Due,
+    BrewDone,
+    KeepWarmOver,
+    ReadyExpired,
+    Fault,
+    ClearFault,
+    Shutdown,
+}
+
+/** Decides between rows for the same state and input */
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Guard {
+    Always,
+    SomethingDue,
+    NothingDue,
+    KeepWarmSet,
+    NoKeepWarm,
+}
+
+/** Run after entering the new state, mostly to start the next timer */
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Action {
+    Nothing,
+    /** Ends the brew after its brew time, if it has one */
+    StartBrew,
+    WaitForDue,
+    StartReadyExpiry,
+    StartKeepWarm,
+}
+
+#[derive(Debug)]
+pub struct Row {
+    pub from: &'static [PotState],
+    pub input: Input,
+    pub guard: Guard,
+    pub to: PotState,
+    pub action: Action,
+}
+
+const fn row(from: &'static [PotState], input: Input, guard: Guard, to: PotState, action: Action) -> Row {
+    Row {
+        from,
+        input,
+        guard,
+        to,
+        action,
+    }
+}
+
+const ARMED: &[PotState] = &[Ready, Waiting];
+const NOT_FAULTED: &[PotState] = &[Idle, Ready, Waiting, Active, KeepWarm];
+const RUNNING: &[PotState] = &[Idle, Ready, Waiting, Active, KeepWarm, Fault];
+
+/**
+ * The whole state machine. The first row matching the current state, input
+ * and guard wins, and an input without a matching row is refused.
+ */
+#[rustfmt::skip]
+pub const TABLE: &[Row] = &[
+    row(&[Idle], Input::ToggleReady, Guard::SomethingDue, Waiting, Action::WaitForDue),
+    row(&[Idle], Input::ToggleReady, Guard::NothingDue, Ready, Action::StartReadyExpiry),
+    row(ARMED, Input::ToggleReady, Guard::Always, Idle, Action::Nothing),
+    row(&[Active, KeepWarm], Input::TogglePower, Guard::Always, Idle, Action::Nothing),
+    row(&[Idle, Ready, Waiting], Input::TogglePower, Guard::Always, Active, Action::StartBrew),
+    row(NOT_FAULTED, Input::Activate, Guard::Always, Active, Action::StartBrew),
+    row(NOT_FAULTED, Input::Inactivate, Guard::Always, Idle, Action::Nothing),
+    row(ARMED, Input::Schedule, Guard::SomethingDue, Waiting, Action::WaitForDue),
+    row(ARMED, Input::Schedule, Guard::NothingDue, Ready, Action::StartReadyExpiry),
+    row(ARMED, Input::ScheduleChanged, Guard::SomethingDue, Waiting, Action::WaitForDue),
+    row(ARMED, Input::ScheduleChanged, Guard::NothingDue, Ready, Action::StartReadyExpiry),
+    row(&[Waiting], Input::CancelPending, Guard::SomethingDue, Waiting, Action::WaitForDue),
+    row(&[Waiting], Input::CancelPending, Guard::NothingDue, Ready, Action::StartReadyExpiry),
+    row(&[Waiting], Input::ShiftPending, Guard::SomethingDue, Waiting, Action::WaitForDue),
+    row(&[Waiting], Input::ShiftPending, Guard::NothingDue, Ready, Action::StartReadyExpiry),
+    // editing the schedule doesn't touch the relay, so it's fine while faulted
+    row(&[Idle], Input::ScheduleChanged, Guard::Always, Idle, Action::Nothing),
+    row(&[Active], Input::ScheduleChanged, Guard::Always, Active, Action::Nothing),
+    row(&[KeepWarm], Input::ScheduleChanged, Guard::Always, KeepWarm, Action::Nothing),
+    row(&[Fault], Input::ScheduleChanged, Guard::Always, Fault, Action::Nothing),
+    row(&[Waiting], Input::Due, Guard::Always, Active, Action::StartBrew),
+    row(&[Active], Input::BrewDone, Guard::KeepWarmSet, KeepWarm, Action::StartKeepWarm),
+    row(&[Active], Input::BrewDone, Guard::NoKeepWarm, Idle, Action::Nothing),
+    row(&[KeepWarm], Input::KeepWarmOver, Guard::Always, Idle, Action::Nothing),
+    row(ARMED, Input::ReadyExpired, Guard::Always, Idle, Action::Nothing),
+    row(RUNNING, Input::Fault, Guard::Always, Fault, Action::Nothing),
+    row(&[Fault], Input::ClearFault, Guard::Always, Idle, Action::Nothing),
+    row(RUNNING, Input::Shutdown, Guard::Always, Shutdown, Action::Nothing),
+];
+
+/** Whether `input` can ever be taken in `state`, whatever the guards say */
+pub fn accepts(state: PotState, input: Input) -> bool {
+    TABLE.iter().any(|row| row.input == input && row.from.contains(&state))
+}
+
+pub fn find<F: Fn(Guard) -> bool>(state: PotState, input: Input, holds: F) -> Option<&'static Row> {
+    TABLE
+        .iter()
+        .find(|row| row.input == input && row.from.contains(&state) && holds(row.guard))
+}
+
+/** One `(from, to, label)` per state a row applies to */
+fn edges() -> impl Iterator<Item = (PotState, PotState, String)> {
+    TABLE.iter().flat_map(|row| {
+        let mut label = format!("{:?}", row.input);
+        if row.guard != Guard::Always {
+            write!(label, " [{:?}]", row.guard).unwrap();
+        }
+        if row.action != Action::Nothing {
+            write!(label, " / {:?}", row.action).unwrap();
+        }
+        row.from.iter().map(move |from| (*from, row.to, label.clone()))
+    })
+}
+
+pub fn graphviz() -> String {
+    let mut out = String::from("digraph coffeepot {\n");
+    for (from, to, label) in edges() {
+        writeln!(out, "    {:?} -> {:?} [label=\"{}\"];", from, to, label).unwrap();
+    }
+    out.push('}');
+    out
+}
+
+pub fn mermaid() -> String {
+    let mut out = String::from("stateDiagram-v2\n    [*] --> Idle\n");
+    for (from, to, label) in edges() {
+        writeln!(out, "    {:?} --> {:?}: {}", from, to, label).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: &[PotState] = &[Idle, Ready, Waiting, Active, Shutdown, KeepWarm, Fault];
+    const INPUTS: &[Input] = &[
+        Input::ToggleReady,
+        Input::TogglePower,
+        Input::Activate,
+        Input::Inactivate,
+        Input::Schedule,
+        Input::ScheduleChanged,
+        Input::CancelPending,
+        Input::ShiftPending,
+        Input::Due,
+        Input::BrewDone,
+        Input::KeepWarmOver,
+        Input::ReadyExpired,
+        Input::Fault,
+        Input::ClearFault,
+        Input::Shutdown,
+    ];
+
+    /** Every combination of guards that can hold at the same time */
+    fn worlds() -> Vec<impl Fn(Guard) -> bool> {
+        [(true, true), (true, false), (false, true), (false, false)]
+            .iter()
+            .map(|&(due, keep_warm)| {
+                move |guard| match guard {
+                    Guard::Always => true,
+                    Guard::SomethingDue => due,
+                    Guard::NothingDue => !due,
+                    Guard::KeepWarmSet => keep_warm,
+                    Guard::NoKeepWarm => !keep_warm,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_row_is_reachable() {
+        for row in TABLE {
+            for &from in row.from {
+                let found = find(from, row.input, |guard| guard == Guard::Always || guard == row.guard);
+                assert_eq!(found.map(|r| r.to), Some(row.to), "{:?} in {:?} [{:?}]", row.input, from, row.guard);
+            }
+        }
+    }
+
+    #[test]
+    fn accepted_inputs_always_have_a_row() {
+        for &state in STATES {
+            for &input in INPUTS {
+                for holds in worlds() {
+                    let found = find(state, input, holds).is_some();
+                    assert_eq!(found, accepts(state, input), "{:?} in {:?}", input, state);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inputs_without_a_row_are_refused() {
+        for &input in INPUTS {
+            assert!(!accepts(Shutdown, input), "{:?} after shutdown", input);
+        }
+        let refused = [
+            (Idle, Input::Schedule),
+            (Idle, Input::Due),
+            (Idle, Input::CancelPending),
+            (Ready, Input::ShiftPending),
+            (Ready, Input::Due),
+            (Active, Input::ToggleReady),
+            (Active, Input::Schedule),
+            (Idle, Input::BrewDone),
+            (Waiting, Input::KeepWarmOver),
+            (Active, Input::ReadyExpired),
+            (Idle, Input::ClearFault),
+            (Fault, Input::Activate),
+            (Fault, Input::TogglePower),
+            (Fault, Input::ToggleReady),
+            (Fault, Input::Schedule),
+        ];
+        for &(state, input) in &refused {
+            assert!(!accepts(state, input), "{:?} in {:?}", input, state);
+            assert!(find(state, input, |_| true).is_none(), "{:?} in {:?}", input, state);
+        }
+    }
+}
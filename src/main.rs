@@ -1,86 +1,277 @@
 mod coffeepot;
+mod command;
+mod config;
+#[cfg(target_arch = "arm")]
 mod debounce;
+mod machine;
+mod mqtt;
+mod persist;
+mod profile;
+mod recurring;
+mod scheduler;
+mod subscribers;
+mod watchdog;
 #[macro_use] extern crate log;
 extern crate simplelog;
-use chrono::prelude::*;
-use coffeepot::Coffeepot;
-use rumqtt::{MqttClient, MqttOptions, Notification, QoS, Receiver, ReconnectOptions};
-use rumqtt::mqttoptions::SecurityOptions;
+use coffeepot::{Brew, Cause, Coffeepot, CoffeepotBuilder, CoffeepotError, Source, Transition, BREWER};
+use command::{Command, Request};
+use config::Config;
+use mqtt::Mqtt;
+use persist::{ProfilesFile, RulesFile, StateFile};
+use rumqtt::{Notification, Receiver};
 use std::env;
 use std::error::Error;
 use std::io::stdin;
+use std::sync::mpsc;
+
+/** Output for the bean grinder, which profiles can run in a stage before brewing */
+pub const GRINDER: &str = "grinder";
+/** Every output there is hardware for */
+pub const OUTPUTS: &[&str] = &[BREWER, GRINDER];
+
+/**
+ * Applies the brew settings from the config, which have been validated, and
+ * the brew profiles in its profiles file.
+ */
+pub fn configure(mut builder: CoffeepotBuilder, config: &Config) -> CoffeepotBuilder {
+    let brew = &config.brew;
+    if let Some(minutes) = brew.minutes {
+        builder = builder.brew_time(chrono::Duration::minutes(minutes));
+    }
+    builder = builder
+        .max_brew_time(chrono::Duration::minutes(brew.max_minutes))
+        .keep_warm_time(chrono::Duration::minutes(brew.keep_warm_minutes));
+    if let Some(hours) = brew.ready_expiry_hours {
+        builder = builder.ready_expiry(chrono::Duration::hours(hours));
+    }
+    match ProfilesFile::new(config.profiles_file()).load() {
+        Ok(mut profiles) => {
+            let max_on = chrono::Duration::minutes(brew.max_on_minutes);
+            let keep_warm = chrono::Duration::minutes(brew.keep_warm_minutes);
+            profiles.retain(|profile| match profile.outputs().find(|output| !OUTPUTS.contains(output)) {
+                Some(output) => {
+                    error!("skipping brew profile {}, there is no output {}", profile.name, output);
+                    false
+                }
+                None if profile.brew_time + profile.keep_warm_time.unwrap_or(keep_warm) >= max_on => {
+                    error!("skipping brew profile {}, the watchdog would trip during it", profile.name);
+                    false
+                }
+                None => true,
+            });
+            for profile in &profiles {
+                info!("brew profile {}", profile);
+            }
+            builder = builder.profiles(profiles);
+        }
+        Err(e) => error!("could not load brew profiles: {}", e),
+    }
+    builder
+}
+
+/** Picks up where we left off before a restart, as far as the restore policy allows */
+pub fn setup(coffeepot: &Coffeepot, config: &Config) {
+    coffeepot.load_rules(RulesFile::new(config.rules_file()));
+    coffeepot.restore(StateFile::new(config.state_file()), config.restore);
+}
+
+/**
+ * Returns the ids of anything the command created, as fields to add to its
+ * acknowledgement, and the state the command left the pot in
+ */
+fn execute(
+    coffeepot: &Coffeepot,
+    mqtt: &mut Mqtt,
+    command: Command,
+    source: Source,
+) -> Result<(serde_json::Value, coffeepot::PotState), CoffeepotError> {
+    let nothing = serde_json::json!({});
+    let done = |transition: Transition| (nothing.clone(), transition.to);
+    Ok(match command {
+        Command::Activate(brew) => done(coffeepot.activate(brew, source)?),
+        Command::Inactivate => done(coffeepot.inactivate(source)?),
+        Command::ActivateDelayed(when, brew) => {
+            debug!("delay {:?}", when);
+            let at = when.after(coffeepot.now()).ok_or(CoffeepotError::OutOfRange)?;
+            done(coffeepot.activate_delayed(brew, at, source)?)
+        }
+        Command::Schedule(when, brew) => {
+            let at = when.after(coffeepot.now()).ok_or(CoffeepotError::OutOfRange)?;
+            let (id, transition) = coffeepot.schedule(brew, at, source)?;
+            info!("scheduled activation {} at {}", id, at);
+            (serde_json::json!({ "schedule_id": id }), transition.to)
+        }
+        Command::CancelScheduled(id) => done(coffeepot.cancel_scheduled(id, source)?),
+        Command::CancelPending => done(coffeepot.cancel_pending(source)?),
+        Command::ShiftPending(minutes) => {
+            let (id, transition) = coffeepot.shift_pending(chrono::Duration::minutes(minutes), source)?;
+            (serde_json::json!({ "schedule_id": id }), transition.to)
+        }
+        Command::ListSchedule => {
+            let listing: String = coffeepot
+                .scheduled()
+                .iter()
+                .map(|e| {
+                    format!(
+                        "{} {} {}\n",
+                        e.id,
+                        e.activation.at.to_rfc3339(),
+                        e.activation.brew_time.num_minutes()
+                    )
+                })
+                .collect();
+            mqtt.publish("schedule", listing);
+            (nothing, coffeepot.current_state())
+        }
+        Command::AddRule(rule) => {
+            let (id, transition) = coffeepot.add_rule(rule, source)?;
+            (serde_json::json!({ "rule_id": id }), transition.to)
+        }
+        Command::RemoveRule(id) => done(coffeepot.remove_rule(id, source)?),
+        Command::ClearFault => done(coffeepot.clear_fault(source)?),
+        Command::ListRules => {
+            let listing: String = coffeepot
+                .rules()
+                .iter()
+                .map(|rule| format!("{} {}\n", rule.id, rule))
+                .collect();
+            mqtt.publish("rules", listing);
+            (nothing, coffeepot.current_state())
+        }
+    })
+}
+
+pub fn publish_event(mqtt: &mut Mqtt, event: &str) {
+    info!("publishing event {}", event);
+    mqtt.publish("event", event);
+}
+
+/** `--graphviz` or `--mermaid` prints the state machine instead of running it */
+pub fn print_diagram() -> bool {
+    match env::args().nth(1).as_deref() {
+        Some("--graphviz") => println!("{}", machine::graphviz()),
+        Some("--mermaid") => println!("{}", machine::mermaid()),
+        _ => return false,
+    }
+    true
+}
+
+/** How long to wait for subscribers to switch everything off on exit */
+pub const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/** Latches the fault state and raises the alarm every time the watchdog trips */
+fn handle_alarms(coffeepot: Coffeepot, mut mqtt: Mqtt, trips: mpsc::Receiver<()>) {
+    for _ in trips {
+        error!("watchdog tripped, relay forced off");
+        mqtt.publish("alarm", "max relay on-time exceeded");
+        coffeepot.fault(Cause::Watchdog)
+            .map_err(|e| error!("could not enter fault state: {}", e))
+            .ok();
+    }
+}
 
-pub fn init_mqtt(url: &str, port: u16) -> (MqttClient, Receiver<Notification>) {
-    let creds = env::var_os("COFFEEPOT_USER")
-        .and_then(|user_os| user_os.into_string().ok())
-        .and_then(|user|
-                  env::var_os("COFFEEPOT_PASS")
-                  .and_then(|pass_os| pass_os.into_string().ok())
-                  .map(|pass|
-                       SecurityOptions::UsernamePassword(user,pass)
-                      )
-    ).unwrap_or(SecurityOptions::None);
-
-    let reconnection_options = ReconnectOptions::Always(10);
-    let mqtt_options = MqttOptions::new("coffeepot", url, port)
-        .set_keep_alive(10)
-        .set_inflight(3)
-        .set_request_channel_capacity(10)
-        .set_reconnect_opts(reconnection_options)
-        .set_security_opts(creds)
-        .set_clean_session(false);
-
-    let (mut mqtt_client, notifications) = MqttClient::start(mqtt_options).unwrap();
-    mqtt_client
-        .subscribe("coffeepot/actions", QoS::AtLeastOnce)
-        .unwrap();
-    (mqtt_client, notifications)
+/**
+ * Publishes `{"brewing_in": <minutes>}` while Waiting and `{"auto_off_in":
+ * <minutes>}` during a timed brew to `coffeepot/countdown` once a minute, and
+ * `{}` once when there is nothing left to count down.
+ */
+fn publish_countdown(coffeepot: Coffeepot, mut mqtt: Mqtt) {
+    // rounded up, so that the last minute reads 1 rather than 0
+    let minutes = |d: chrono::Duration| (d.num_seconds() + 59) / 60;
+    let nothing = serde_json::json!({});
+    let mut last = nothing.clone();
+    while coffeepot.current_state() != coffeepot::PotState::Shutdown {
+        let countdown = match (coffeepot.scheduled_activation(), coffeepot.remaining_active()) {
+            (Some(activation), _) => serde_json::json!({ "brewing_in": minutes(activation.at - coffeepot.now()) }),
+            (_, Some(left)) => serde_json::json!({ "auto_off_in": minutes(left) }),
+            _ => nothing.clone(),
+        };
+        if countdown != nothing || last != nothing {
+            mqtt.publish("countdown", countdown.to_string());
+        }
+        last = countdown;
+        std::thread::sleep(std::time::Duration::from_secs(60));
+    }
 }
 
-/** Allow actions to be injected from network for home automation */
-fn handle_notifications(coffeepot: Coffeepot, notifications: Receiver<Notification>) {
+/**
+ * Answers a command on `coffeepot/response` with `{"id": <request id>,
+ * "status": "accepted" | "rejected", "reason": <why it was rejected>,
+ * "state": <state afterwards>}`, plus the ids of anything it created
+ */
+fn acknowledge(mqtt: &mut Mqtt, id: Option<String>, result: Result<serde_json::Value, String>, state: coffeepot::PotState) {
+    let mut ack = match result {
+        Ok(mut created) => {
+            created["status"] = serde_json::json!("accepted");
+            created
+        }
+        Err(reason) => serde_json::json!({ "status": "rejected", "reason": reason }),
+    };
+    ack["state"] = serde_json::json!(state);
+    if let Some(id) = id {
+        ack["id"] = serde_json::json!(id);
+    }
+    mqtt.publish("response", ack.to_string());
+}
+
+/**
+ * Allow actions to be injected from network for home automation, as JSON on
+ * `coffeepot/cmd` or single letter commands on `coffeepot/actions`. Every
+ * command is acknowledged, including those that couldn't be parsed.
+ */
+fn handle_notifications(coffeepot: Coffeepot, mut mqtt: Mqtt, notifications: Receiver<Notification>) {
+    let json_topic = mqtt.topic("cmd");
     for notification in notifications {
-        match notification {
-            Notification::Publish(packet) => {
-                if packet.payload.len() < 1 {
-                    warn!("payload empty!");
-                    continue;
+        let packet = match notification {
+            Notification::Publish(packet) => packet,
+            Notification::Reconnection => {
+                info!("reconnected to mqtt");
+                mqtt.republish();
+                continue;
+            }
+            Notification::Disconnection => {
+                warn!("disconnected from mqtt");
+                continue;
+            }
+            _ => continue,
+        };
+        debug!("payload received {:?}", packet.payload);
+        let json = packet.topic_name == json_topic;
+        let parsed = if json {
+            command::parse_json(&packet.payload)
+        } else {
+            command::parse(&packet.payload).map(|command| Request { id: None, command })
+        };
+        let (id, result, state) = match parsed {
+            Ok(Request { id, command }) => {
+                if let Some(id) = &id {
+                    debug!("request {}: {:?}", id, command);
                 }
-                debug!("payload received {:?}", packet.payload);
-                match packet.payload[0] as char {
-                    'a' => coffeepot.activate(chrono::Duration::seconds(2)),
-                    'i' => coffeepot.inactivate(),
-                    'd' => {
-                        const MINUTES: i32 = 60;
-                        if packet.payload.len() == 1 {
-                            coffeepot.activate_delayed(
-                                chrono::Duration::minutes(45),
-                                Local::now() + FixedOffset::east(5 * MINUTES),
-                                );
-                                continue;
-                        };
-                        let delay_str: Result<&str, Box<dyn Error>> = std::str::from_utf8(&packet.payload[1..])
-                            .map_err(|e| e.into());
-                        match delay_str.and_then(|s| s.parse::<i64>().map_err(|e| e.into())) {
-                            Ok(delay) => {
-                                debug!("delay {}", delay);
-                                coffeepot.activate_delayed(
-                                    chrono::Duration::minutes(90),
-                                    Local::now() + chrono::Duration::minutes(delay),
-                                    )
-                            },
-                            Err(e) => error!("vec -> string -> int failed: {}", e)
-                        }
-                    },
-                    other => warn!("unexpected input: {}", other),
+                match execute(&coffeepot, &mut mqtt, command.clone(), Source::mqtt(&packet.topic_name)) {
+                    Ok((created, state)) => (id, Ok(created), state),
+                    Err(e) => {
+                        warn!("rejected {:?}: {}", command, e);
+                        publish_event(&mut mqtt, &format!("rejected: {}", e));
+                        // nothing changed, so whatever the state is now
+                        (id, Err(e.to_string()), coffeepot.current_state())
+                    }
                 }
-                info!("state: {:?}", coffeepot.current_state());
             }
-            _ => (),
-        }
+            Err(e) => {
+                warn!("{}", e);
+                // still answer the right request if the id can be made out
+                let id = if json { command::request_id(&packet.payload) } else { None };
+                (id, Err(format!("invalid command: {}", e)), coffeepot.current_state())
+            }
+        };
+        acknowledge(&mut mqtt, id, result, state);
+        info!("state: {:?}", state);
     }
 }
 
+/** Seconds `d` waits in the demo, which is for watching transitions rather than brewing */
+const DEMO_DELAY: i64 = 5;
+
 /** Allows actions to be injected from the terminal for testing purposes */
 #[allow(dead_code)]
 fn demo(coffeepot: Coffeepot) -> Result<(), Box<dyn Error>> {
@@ -91,16 +282,28 @@ fn demo(coffeepot: Coffeepot) -> Result<(), Box<dyn Error>> {
         stdin()
             .read_line(&mut input)
             .expect("Did not enter a correct string");
-        match input.trim().as_ref() {
-            "a" => coffeepot.activate(chrono::Duration::seconds(2)),
-            "i" => coffeepot.inactivate(),
-            "r" => coffeepot.toggle_ready(),
+        let result = match input.trim() {
+            "a" => coffeepot.activate(Brew::Default, Cause::Terminal),
+            "i" => coffeepot.inactivate(Cause::Terminal),
+            "r" => coffeepot.toggle_ready(Cause::Terminal),
+            "p" => coffeepot.toggle_active(Cause::Terminal),
+            "f" => coffeepot.clear_fault(Cause::Terminal),
             "d" => coffeepot.activate_delayed(
-                chrono::Duration::seconds(5),
-                Local::now() + FixedOffset::east(5),
+                Brew::Default,
+                coffeepot.now() + chrono::Duration::seconds(DEMO_DELAY),
+                Cause::Terminal,
             ),
-            "e" => exit = true,
-            other => warn!("unexpected input: {}", other),
+            "e" => {
+                exit = true;
+                continue;
+            }
+            other => {
+                warn!("unexpected input: {}", other);
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            warn!("rejected: {}", e);
         }
         info!("state: {:?}", coffeepot.current_state());
     }
@@ -115,28 +318,57 @@ fn main() -> Result<(), Box<dyn Error>> {
     use coffeepot::PotState;
     use simplelog::*;
     use std::thread;
+    use watchdog::Watchdog;
+    if print_diagram() {
+        return Ok(());
+    }
+    let config = crate::Config::load(&env::args().skip(1).collect::<Vec<_>>())?;
     println!("Hello, world!");
     TermLogger::init(log::LevelFilter::Debug, Config::default(), TerminalMode::Mixed, ColorChoice::Auto).unwrap();
-    let (tx, rx) = init_mqtt("test.mosquitto.org", 1883);
-    let coffeepot = Coffeepot::new({
-        let mut tx = tx;
-        move |new_state: PotState| {
-            println!("state changed to {:?}", new_state);
-            tx.publish(
-                "coffeepot/state",
-                QoS::AtLeastOnce,
-                false,
-                vec![new_state as u8],
-            )
-            .expect("mqtt publish failed");
-            println!("state changed to {:?}_____", new_state);
+    let (mut tx, rx) = mqtt::connect(&config.mqtt)?;
+    let (trip_tx, trip_rx) = mpsc::channel();
+    let watchdog = Watchdog::new(config.brew.max_on_time(), move || {
+        trip_tx.send(()).ok();
+    });
+    // short brews, so the demo doesn't take all morning
+    let coffeepot = configure(Coffeepot::builder().brew_time(chrono::Duration::seconds(5)), &config).build();
+    coffeepot.subscribe("logger", subscribers::logger);
+    coffeepot.subscribe("relay", {
+        move |transition: coffeepot::Transition| {
+            let new_state = transition.to;
+            if transition.from == PotState::Fault && new_state != PotState::Fault {
+                watchdog.reset();
+            }
+            let tripped = watchdog.is_tripped();
+            for output in OUTPUTS {
+                let on = transition.outputs.iter().any(|o| o == output) && !tripped;
+                println!("{} {}", output, if on { "on" } else { "off" });
+            }
+            watchdog.relay_changed(!transition.outputs.is_empty() && !tripped);
         }
     });
+    coffeepot.subscribe("mqtt", subscribers::mqtt_publisher(tx.clone()));
+    coffeepot.subscribe("stats", subscribers::stats());
+    setup(&coffeepot, &config);
+    thread::spawn({
+        let coffeepot = coffeepot.clone();
+        let tx = tx.clone();
+        move || handle_alarms(coffeepot, tx, trip_rx)
+    });
+    thread::spawn({
+        let coffeepot = coffeepot.clone();
+        let tx = tx.clone();
+        move || publish_countdown(coffeepot, tx)
+    });
     thread::spawn({
         let coffeepot = coffeepot.clone();
-        move || handle_notifications(coffeepot, rx)
+        let tx = tx.clone();
+        move || handle_notifications(coffeepot, tx, rx)
     });
-    demo(coffeepot)
+    demo(coffeepot.clone())?;
+    coffeepot.shutdown(SHUTDOWN_TIMEOUT);
+    tx.disconnect();
+    Ok(())
 }
 
 /* ***** Pi hardware dependent stuff below here ******* */
@@ -148,14 +380,13 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 #[cfg(target_arch = "arm")]
 mod pi {
-    use crate::coffeepot::{Coffeepot, PotState};
+    use crate::coffeepot::{Cause, Coffeepot, PotState, Transition, BREWER};
     use crate::debounce;
-    use rppal::gpio::{Gpio, Level};
+    use crate::watchdog::Watchdog;
+    use rppal::gpio::{Gpio, Level, OutputPin};
     use rppal::pwm::{Channel, Polarity, Pwm};
-    use rumqtt::QoS;
     use std::error::Error;
-    #[cfg(not(debug_assertions))]
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
     #[cfg(not(debug_assertions))]
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
@@ -164,22 +395,57 @@ mod pi {
     use simplelog::*;
     use simplelog::{Level as LogLevel};
 
-    // Gpio uses BCM pin numbering
-    const GPIO_READY_BUTTON_PIN: u8 = 17;
-    const GPIO_POWER_BUTTON_PIN: u8 = 22;
-    const GPIO_RELAY_CTRL_PIN: u8 = 27;
     const PWM_READY_LED_PIN: Channel = Channel::Pwm1;
     const PWM_POWER_LED_PIN: Channel = Channel::Pwm0;
 
-    fn pwm_cycle_wait(led: &Pwm, rx: &Receiver<Action>) -> bool {
+    /** Relays by output name */
+    struct Outputs(Vec<(&'static str, OutputPin)>);
+
+    impl Outputs {
+        /** Switches on the outputs in `on` and everything else off */
+        fn set(&mut self, on: &[String]) {
+            for (name, pin) in &mut self.0 {
+                let level = if on.iter().any(|o| o == name) { Level::High } else { Level::Low };
+                pin.write(level);
+            }
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    enum Pattern {
+        /** Fade in and out, waiting the given time between each step */
+        Breathe(Duration),
+        /** Switch on and off, waiting the given time in between */
+        Blink(Duration),
+    }
+
+    impl Pattern {
+        fn levels(self) -> (Vec<f64>, Duration) {
+            match self {
+                Pattern::Breathe(step) => (
+                    (0..100).chain((0..100).rev()).map(|x| (x as f64) / 100.0).collect(),
+                    step,
+                ),
+                Pattern::Blink(step) => (vec![0.9, 0.0], step),
+            }
+        }
+    }
+
+    const WAITING_PATTERN: Pattern = Pattern::Breathe(Duration::from_millis(10));
+    const KEEP_WARM_PATTERN: Pattern = Pattern::Breathe(Duration::from_millis(40));
+    const FAULT_PATTERN: Pattern = Pattern::Blink(Duration::from_millis(100));
+    const READY_EXPIRED_PATTERN: Pattern = Pattern::Blink(Duration::from_millis(1000));
+
+    /** Returns the pattern to resume cycling with, or `None` on exit */
+    fn pwm_cycle_wait(led: &Pwm, rx: &Receiver<Action>) -> Option<Pattern> {
         match rx.recv() {
-            Ok(Action::Start) => false,
+            Ok(Action::Start(pattern)) => Some(pattern),
             Ok(Action::Stop(brightness)) => {
                 led.set_duty_cycle(brightness).unwrap();
                 pwm_cycle_wait(led, rx)
             },
-            Ok(Action::Exit) => true,
-            Err(_) => true,
+            Ok(Action::Exit) => None,
+            Err(_) => None,
         }
     }
 
@@ -192,39 +458,49 @@ mod pi {
             true,
         )
         .expect("Could not setup pwm pin");
-        let mut exit = false;
-        while !exit {
-            for x in (0..100).chain((0..100).rev()) {
-                led.set_duty_cycle((x as f64) / 100.0).unwrap();
-                exit = match rx.recv_timeout(Duration::from_millis(10)) {
+        let mut pattern = WAITING_PATTERN;
+        'cycle: loop {
+            let (levels, step) = pattern.levels();
+            for level in levels {
+                led.set_duty_cycle(level).unwrap();
+                let next = match rx.recv_timeout(step) {
                     Ok(Action::Stop(brightness)) => {
                         led.set_duty_cycle(brightness)
                             .expect("could not set ready led duty cycle");
                         pwm_cycle_wait(&led, &rx)
                     }
-                    Err(RecvTimeoutError::Timeout) => false,
-                    Ok(Action::Start) => false,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Ok(Action::Start(new_pattern)) => Some(new_pattern),
                     Err(e) => {
                         println!("error while receiving in pwm thread: {}", e);
-                        true
+                        None
                     },
-                    Ok(Action::Exit) => true,
+                    Ok(Action::Exit) => None,
                 };
-                if exit {
-                    break;
+                match next {
+                    Some(new_pattern) => {
+                        pattern = new_pattern;
+                        continue 'cycle;
+                    },
+                    None => break 'cycle,
                 }
             }
         }
     }
 
     enum Action {
-        Start,
+        Start(Pattern),
         Stop(f64),
         Exit,
     }
 
     /** This is the actual main function running in production on rpi hardware */
     pub fn main() -> Result<(), Box<dyn Error>> {
+        if crate::print_diagram() {
+            return Ok(());
+        }
+        let config = crate::Config::load(&std::env::args().skip(1).collect::<Vec<_>>())?;
+        let pins = &config.pins;
         let log_config = ConfigBuilder::new()
             .set_level_color(LogLevel::Error, Some(Color::Rgb(191, 0, 0)))
             .set_level_color(LogLevel::Warn, Some(Color::Rgb(255, 127, 0)))
@@ -240,13 +516,25 @@ mod pi {
         TermLogger::init(log::LevelFilter::Debug, log_config, TerminalMode::Mixed, ColorChoice::Auto)?;
         #[cfg(not(debug_assertions))]
         {
-            let log_file = std::fs::File::create("/var/log/coffeepot.log").expect("could not open log file");
+            let log_file = std::fs::File::create(&config.log).expect("could not open log file");
             WriteLogger::init(LevelFilter::Debug, log_config, log_file)?;
         }
         info!("booting up coffeepot");
-        let mut ready_input = Gpio::new()?.get(GPIO_READY_BUTTON_PIN)?.into_input_pulldown();
-        let mut power_input = Gpio::new()?.get(GPIO_POWER_BUTTON_PIN)?.into_input_pulldown();
-        let mut relay_output = Gpio::new()?.get(GPIO_RELAY_CTRL_PIN)?.into_output();
+        let mut ready_input = Gpio::new()?.get(pins.ready_button)?.into_input_pulldown();
+        let mut power_input = Gpio::new()?.get(pins.power_button)?.into_input_pulldown();
+        // shared with the watchdog, which must be able to force them off on its own
+        let outputs = Arc::new(Mutex::new(Outputs(vec![
+            (BREWER, Gpio::new()?.get(pins.relay)?.into_output()),
+            (crate::GRINDER, Gpio::new()?.get(pins.grinder)?.into_output()),
+        ])));
+        let (trip_tx, trip_rx) = channel();
+        let watchdog = Watchdog::new(config.brew.max_on_time(), {
+            let outputs = outputs.clone();
+            move || {
+                outputs.lock().unwrap().set(&[]);
+                trip_tx.send(()).ok();
+            }
+        });
 
         let (pwm_tx, pwm_rx) = channel();
         // have to clone before first recv_timeout to avoid panic bug
@@ -261,58 +549,60 @@ mod pi {
         )
         .expect("Could not setup pwm power pin");
         info!("initialised pins");
-        let (mqtt_tx, mqtt_rx) = crate::init_mqtt("bosch.hnrklssn.se", 1883);
+        let (mut mqtt_tx, mqtt_rx) = crate::mqtt::connect(&config.mqtt)?;
         info!("connected to mqtt");
 
-        let coffeepot = Coffeepot::new({
+        let coffeepot = crate::configure(Coffeepot::builder(), &config).build();
+        coffeepot.subscribe("logger", crate::subscribers::logger);
+        coffeepot.subscribe("leds", {
             let pwm_tx = pwm_tx2;
-            let mut mqtt_tx = mqtt_tx;
-            move |new_state| {
-                info!("state changed to {:?}", new_state);
-                    let result = if new_state == PotState::Waiting {
-                        pwm_tx.send(Action::Start)
-                    } else if new_state == PotState::Idle {
-                        pwm_tx.send(Action::Stop(0.0))
-                    } else {
-                        pwm_tx.send(Action::Stop(0.9))
-                    };
-                    match result {
-                        Err(e) => {
-                            error!("error sending to pwm: {}", e);
-                            std::panic!("error sending to pwm: {}", e);
-                        },
-                        _ => (),
-                    }
+            move |transition: Transition| {
+                let new_state = transition.to;
+                let result = match new_state {
+                    _ if transition.is_ready_expiry() => pwm_tx.send(Action::Start(READY_EXPIRED_PATTERN)),
+                    PotState::Waiting => pwm_tx.send(Action::Start(WAITING_PATTERN)),
+                    PotState::KeepWarm => pwm_tx.send(Action::Start(KEEP_WARM_PATTERN)),
+                    PotState::Fault => pwm_tx.send(Action::Start(FAULT_PATTERN)),
+                    PotState::Idle | PotState::Shutdown => pwm_tx.send(Action::Stop(0.0)),
+                    _ => pwm_tx.send(Action::Stop(0.9)),
+                };
+                if let Err(e) = result {
+                    error!("error sending to pwm: {}", e);
+                    std::panic!("error sending to pwm: {}", e);
+                }
 
                 let power_brightness = match new_state {
-                    PotState::Idle | PotState::Active => 0.1,
+                    PotState::Idle | PotState::Active | PotState::KeepWarm => 0.1,
                     _ => 0.0,
                 };
                 power_led.set_duty_cycle(power_brightness)
                     .map_err(|_| error!("could not set duty cycle of power led"))
                     .expect("could not set duty cycle of power led");
-
-                let relay_level = match new_state {
-                    PotState::Active => Level::High,
-                    _ => Level::Low,
-                };
-                relay_output.write(relay_level);
-                mqtt_tx.publish(
-                    "coffeepot/state",
-                    QoS::AtLeastOnce,
-                    false,
-                    vec![new_state as u8],
-                    )
-                    .map_err(|_| error!("mqtt publish failed"))
-                    .expect("mqtt publish failed");
             }
         });
+        coffeepot.subscribe("relay", {
+            let outputs = outputs.clone();
+            move |transition: Transition| {
+                let new_state = transition.to;
+                if transition.from == PotState::Fault && new_state != PotState::Fault {
+                    watchdog.reset();
+                }
+                // never switch back on behind the watchdog's back
+                let on = if watchdog.is_tripped() { &[][..] } else { &transition.outputs[..] };
+                outputs.lock().unwrap().set(on);
+                watchdog.relay_changed(!on.is_empty());
+            }
+        });
+        coffeepot.subscribe("mqtt", crate::subscribers::mqtt_publisher(mqtt_tx.clone()));
+        coffeepot.subscribe("stats", crate::subscribers::stats());
         let update_ready = debounce::closure(Level::Low, {
             let coffeepot = coffeepot.clone();
             move |level| {
                 debug!("update ready state {:?}", level);
                 if level == Level::High {
-                    coffeepot.toggle_ready();
+                    coffeepot.toggle_ready(Cause::Button)
+                        .map_err(|e| warn!("ready button rejected: {}", e))
+                        .ok();
                 }
             }
         });
@@ -321,7 +611,9 @@ mod pi {
             move |level| {
                 debug!("update power state {:?}", level);
                 if level == Level::High {
-                    coffeepot.toggle_active();
+                    coffeepot.toggle_active(Cause::Button)
+                        .map_err(|e| warn!("power button rejected: {}", e))
+                        .ok();
                 }
             }
         });
@@ -329,15 +621,28 @@ mod pi {
         power_input.set_async_interrupt(rppal::gpio::Trigger::Both, update_power)?;
         thread::spawn({
             let coffeepot = coffeepot.clone();
-            move || crate::handle_notifications(coffeepot, mqtt_rx)
+            let mqtt_tx = mqtt_tx.clone();
+            move || crate::handle_notifications(coffeepot, mqtt_tx, mqtt_rx)
+        });
+        thread::spawn({
+            let coffeepot = coffeepot.clone();
+            let mqtt_tx = mqtt_tx.clone();
+            move || crate::handle_alarms(coffeepot, mqtt_tx, trip_rx)
+        });
+        thread::spawn({
+            let coffeepot = coffeepot.clone();
+            let mqtt_tx = mqtt_tx.clone();
+            move || crate::publish_countdown(coffeepot, mqtt_tx)
         });
         // make sure main thread dies if pwm thread fails
         pwm_tx.send(Action::Stop(0.0))
             .map_err(|_| error!("pwm thread crashed on startup"))
             .expect("pwm thread crashed on startup");
+        // after the startup pwm message, so the led reflects the restored state
+        crate::setup(&coffeepot, &config);
 
         #[cfg(debug_assertions)]
-        super::demo(coffeepot).expect("demo failed");
+        super::demo(coffeepot.clone()).expect("demo failed");
         #[cfg(not(debug_assertions))]
         {
             let shutdown = Arc::new(AtomicBool::new(false));
@@ -353,6 +658,11 @@ mod pi {
             }
             info!("initiating shutdown");
         }
+        // switches the relays off before the leds go dark
+        if !coffeepot.shutdown(crate::SHUTDOWN_TIMEOUT) {
+            outputs.lock().unwrap().set(&[]);
+        }
+        mqtt_tx.disconnect();
         pwm_tx.send(Action::Exit)?;
         info!("waiting for pwm thread to shut down");
         pwm_thread.join()
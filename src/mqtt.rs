@@ -0,0 +1,120 @@
+use crate::config::MqttConfig;
+use rumqtt::mqttoptions::SecurityOptions;
+use rumqtt::{LastWill, MqttClient, MqttOptions, Notification, QoS, Receiver, ReconnectOptions};
+use std::sync::{Arc, Mutex};
+
+/** Topic saying whether the pot is connected, kept up to date by the broker through the last will */
+const AVAILABILITY: &str = "availability";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/** Payloads by topic name */
+type Retained = Vec<(String, Vec<u8>)>;
+
+/** Publishes below the configured topic prefix, logging anything that fails */
+#[derive(Clone)]
+pub struct Mqtt {
+    client: MqttClient,
+    prefix: String,
+    /** The last retained payload of every topic, in the order first published */
+    retained: Arc<Mutex<Retained>>,
+}
+
+impl Mqtt {
+    /** The full topic for `name`, e.g. `coffeepot/state` for `state` */
+    pub fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+
+    fn send(&mut self, name: &str, payload: Vec<u8>, retain: bool) {
+        let topic = self.topic(name);
+        self.client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(|e| error!("mqtt publish failed: {}", e))
+            .ok();
+    }
+
+    pub fn publish<V: Into<Vec<u8>>>(&mut self, name: &str, payload: V) {
+        self.send(name, payload.into(), false);
+    }
+
+    /** Publishes for the broker to keep, so that new subscribers get it straight away */
+    pub fn publish_retained<V: Into<Vec<u8>>>(&mut self, name: &str, payload: V) {
+        let payload = payload.into();
+        {
+            let mut retained = self.retained.lock().unwrap();
+            match retained.iter_mut().find(|(topic, _)| topic == name) {
+                Some(entry) => entry.1 = payload.clone(),
+                None => retained.push((name.to_string(), payload.clone())),
+            }
+        }
+        self.send(name, payload, true);
+    }
+
+    /**
+     * Publishes every retained payload again after a reconnect, starting with
+     * being online again, in case the broker lost them in the meantime
+     */
+    pub fn republish(&mut self) {
+        let retained = self.retained.lock().unwrap().clone();
+        for (name, payload) in retained {
+            self.send(&name, payload, true);
+        }
+    }
+
+    /** Says goodbye on the availability topic, since the last will isn't sent on a clean disconnect */
+    pub fn disconnect(&mut self) {
+        self.publish_retained(AVAILABILITY, OFFLINE);
+        self.client
+            .shutdown()
+            .map_err(|e| error!("mqtt disconnect failed: {}", e))
+            .ok();
+    }
+}
+
+/**
+ * Connects to the broker, over TLS if configured, announces being online on
+ * `<prefix>/availability` and subscribes to `<prefix>/actions` and
+ * `<prefix>/cmd`. The config has been validated, so the credential and
+ * certificate files are only missing if they went away since.
+ */
+pub fn connect(config: &MqttConfig) -> Result<(Mqtt, Receiver<Notification>), String> {
+    let creds = match config.credentials()? {
+        Some((user, pass)) => SecurityOptions::UsernamePassword(user, pass),
+        None => SecurityOptions::None,
+    };
+
+    let reconnection_options = ReconnectOptions::Always(10);
+    let mut mqtt_options = MqttOptions::new(config.client_id.as_str(), config.host.as_str(), config.port())
+        .set_keep_alive(10)
+        .set_inflight(3)
+        .set_request_channel_capacity(10)
+        .set_reconnect_opts(reconnection_options)
+        .set_security_opts(creds)
+        .set_clean_session(false)
+        .set_last_will(LastWill {
+            topic: format!("{}/{}", config.topic_prefix, AVAILABILITY),
+            message: OFFLINE.to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        });
+    if let Some(tls) = config.tls()? {
+        mqtt_options = mqtt_options.set_ca(tls.ca);
+        if let Some((cert, key)) = tls.client_auth {
+            mqtt_options = mqtt_options.set_client_auth(cert, key);
+        }
+    }
+
+    let (client, notifications) = MqttClient::start(mqtt_options).unwrap();
+    let mut mqtt = Mqtt {
+        client,
+        prefix: config.topic_prefix.clone(),
+        retained: Arc::new(Mutex::new(Vec::new())),
+    };
+    mqtt.publish_retained(AVAILABILITY, ONLINE);
+    for name in &["actions", "cmd"] {
+        let topic = mqtt.topic(name);
+        mqtt.client.subscribe(topic, QoS::AtLeastOnce).unwrap();
+    }
+    Ok((mqtt, notifications))
+}
@@ -0,0 +1,373 @@
+extern crate chrono;
+use crate::coffeepot::{Activation, PotState, ScheduledActivation};
+use crate::profile::BrewProfile;
+use crate::recurring::WeeklyRule;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/** What to bring back from the state file after a restart */
+#[derive(PartialEq, Copy, Clone, Debug, Deserialize)]
+pub enum RestorePolicy {
+    /** Resume Waiting, and an interrupted brew for the rest of its duration */
+    #[serde(rename = "resume")]
+    Resume,
+    /** Resume Waiting, but never turn the relay back on after a restart */
+    #[serde(rename = "never-active")]
+    NeverResumeActive,
+    /** Come back as Ready after any armed state, dropping the schedule */
+    #[serde(rename = "ready")]
+    FallBackToReady,
+}
+
+impl FromStr for RestorePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resume" => Ok(RestorePolicy::Resume),
+            "never-active" => Ok(RestorePolicy::NeverResumeActive),
+            "ready" => Ok(RestorePolicy::FallBackToReady),
+            other => Err(format!("unknown restore policy: {}", other)),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct SavedState {
+    pub state: PotState,
+    pub activation: Option<Activation>,
+    pub schedule: Vec<ScheduledActivation>,
+    pub armed_at: Option<DateTime<Utc>>,
+    /** Weekly rule occurrences that were cancelled or moved, as `(rule id, time)` */
+    pub skipped: Vec<(u32, DateTime<Utc>)>,
+    /** The id the next scheduled activation gets, so that ids aren't reused */
+    pub next_id: u32,
+}
+
+/**
+ * Writes to a temporary file and renames it into place, so a brownout
+ * mid-write leaves the previous contents intact.
+ */
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/** Reads the whole file, or `None` if it doesn't exist yet */
+fn read_if_exists(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/** A saved brew time, if it fits in a `Duration` */
+fn brew_seconds(seconds: i64) -> Option<Duration> {
+    let max = Duration::max_value().num_seconds();
+    if (-max..=max).contains(&seconds) {
+        Some(Duration::seconds(seconds))
+    } else {
+        None
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/**
+ * Plain `key=value` file holding the last state, the current brew, when the
+ * pot was armed, the schedule of pending activations and the next id to give
+ * one, and any skipped weekly rule occurrences.
+ */
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        StateFile { path: path.into() }
+    }
+
+    pub fn save(&self, saved: &SavedState) -> io::Result<()> {
+        let mut contents = format!("state={}\n", saved.state as u8);
+        contents += &format!("next_id={}\n", saved.next_id);
+        if let Some(activation) = &saved.activation {
+            contents += &format!("activation={}\n", activation.at.to_rfc3339());
+            contents += &format!("brew_time={}\n", activation.brew_time.num_seconds());
+            if let Some(profile) = &activation.profile {
+                contents += &format!("profile={}\n", profile);
+            }
+        }
+        if let Some(armed_at) = saved.armed_at {
+            contents += &format!("armed={}\n", armed_at.to_rfc3339());
+        }
+        for entry in &saved.schedule {
+            contents += &format!(
+                "scheduled={} {} {}",
+                entry.id,
+                entry.activation.at.to_rfc3339(),
+                entry.activation.brew_time.num_seconds()
+            );
+            if let Some(profile) = &entry.activation.profile {
+                contents += &format!(" {}", profile);
+            }
+            contents += "\n";
+        }
+        for (id, at) in &saved.skipped {
+            contents += &format!("skipped={} {}\n", id, at.to_rfc3339());
+        }
+        write_atomically(&self.path, &contents)
+    }
+
+    /** Returns `None` if nothing has been saved yet */
+    pub fn load(&self) -> io::Result<Option<SavedState>> {
+        let contents = match read_if_exists(&self.path)? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+        let mut state = None;
+        let mut at = None;
+        let mut brew_time = None;
+        let mut profile = None;
+        let mut schedule = Vec::new();
+        let mut armed_at = None;
+        let mut skipped = Vec::new();
+        let mut next_id = None;
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "state" => {
+                    state = value
+                        .parse::<u8>()
+                        .ok()
+                        .and_then(PotState::from_u8)
+                        .map(Some)
+                        .ok_or_else(|| invalid(format!("invalid state: {}", value)))?
+                }
+                "activation" => {
+                    at = DateTime::parse_from_rfc3339(value)
+                        .map(|t| Some(t.with_timezone(&Utc)))
+                        .map_err(|e| invalid(format!("invalid activation time: {}", e)))?
+                }
+                "brew_time" => {
+                    brew_time = value
+                        .parse::<i64>()
+                        .ok()
+                        .and_then(brew_seconds)
+                        .map(Some)
+                        .ok_or_else(|| invalid(format!("invalid brew time: {}", value)))?
+                }
+                "profile" => profile = Some(value.to_string()),
+                "next_id" => {
+                    next_id = value
+                        .parse::<u32>()
+                        .map(Some)
+                        .map_err(|e| invalid(format!("invalid next id: {}", e)))?
+                }
+                "armed" => {
+                    armed_at = DateTime::parse_from_rfc3339(value)
+                        .map(|t| Some(t.with_timezone(&Utc)))
+                        .map_err(|e| invalid(format!("invalid armed time: {}", e)))?
+                }
+                "scheduled" => schedule.push(
+                    parse_scheduled(value)
+                        .ok_or_else(|| invalid(format!("invalid scheduled activation: {}", value)))?,
+                ),
+                "skipped" => skipped.push(
+                    parse_skipped(value)
+                        .ok_or_else(|| invalid(format!("invalid skipped rule: {}", value)))?,
+                ),
+                other => warn!("ignoring unknown state file key {}", other),
+            }
+        }
+        let state = state.ok_or_else(|| invalid("state missing".to_string()))?;
+        let activation = match (at, brew_time) {
+            (Some(at), Some(brew_time)) => Some(Activation { at, brew_time, profile }),
+            _ => None,
+        };
+        // files saved before the next id was, which only had the schedule to go by
+        let next_id = next_id.unwrap_or_else(|| schedule.iter().map(|e| e.id + 1).max().unwrap_or(1));
+        Ok(Some(SavedState {
+            state,
+            activation,
+            schedule,
+            armed_at,
+            skipped,
+            next_id,
+        }))
+    }
+}
+
+/** Parses `<id> <rfc3339 time> <brew seconds> [<profile>]` */
+fn parse_scheduled(value: &str) -> Option<ScheduledActivation> {
+    let mut parts = value.split_whitespace();
+    let id = parts.next()?.parse().ok()?;
+    let at = DateTime::parse_from_rfc3339(parts.next()?).ok()?;
+    let brew_time = brew_seconds(parts.next()?.parse().ok()?)?;
+    Some(ScheduledActivation {
+        id,
+        activation: Activation {
+            at: at.with_timezone(&Utc),
+            brew_time,
+            profile: parts.next().map(str::to_string),
+        },
+    })
+}
+
+/** Parses `<rule id> <rfc3339 time>` */
+fn parse_skipped(value: &str) -> Option<(u32, DateTime<Utc>)> {
+    let mut parts = value.split_whitespace();
+    let id = parts.next()?.parse().ok()?;
+    let at = DateTime::parse_from_rfc3339(parts.next()?).ok()?;
+    Some((id, at.with_timezone(&Utc)))
+}
+
+/** Weekly brew rules, one `<id> <rule>` line each */
+pub struct RulesFile {
+    path: PathBuf,
+}
+
+impl RulesFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        RulesFile { path: path.into() }
+    }
+
+    pub fn save(&self, rules: &[WeeklyRule]) -> io::Result<()> {
+        let contents: String = rules
+            .iter()
+            .map(|rule| format!("{} {}\n", rule.id, rule))
+            .collect();
+        write_atomically(&self.path, &contents)
+    }
+
+    pub fn load(&self) -> io::Result<Vec<WeeklyRule>> {
+        let contents = read_if_exists(&self.path)?.unwrap_or_default();
+        contents
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|line| {
+                let mut parts = line.trim().splitn(2, ' ');
+                let id = parts.next().unwrap_or("").parse::<u32>();
+                let rule = parts.next().unwrap_or("").parse::<WeeklyRule>();
+                match (id, rule) {
+                    (Ok(id), Ok(rule)) => Ok(rule.with_id(id)),
+                    _ => Err(invalid(format!("invalid rule: {}", line))),
+                }
+            })
+            .collect()
+    }
+}
+
+/** Brew profiles, one `<name> <brew minutes> [<keep warm minutes>]` line each, `#` starts a comment */
+pub struct ProfilesFile {
+    path: PathBuf,
+}
+
+impl ProfilesFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        ProfilesFile { path: path.into() }
+    }
+
+    pub fn load(&self) -> io::Result<Vec<BrewProfile>> {
+        let contents = read_if_exists(&self.path)?.unwrap_or_default();
+        let mut profiles: Vec<BrewProfile> = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let profile = line
+                .parse::<BrewProfile>()
+                .map_err(|e| invalid(format!("invalid profile {}: {}", line, e)))?;
+            if profiles.iter().any(|p| p.name == profile.name) {
+                return Err(invalid(format!("duplicate profile: {}", profile.name)));
+            }
+            profiles.push(profile);
+        }
+        Ok(profiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+    use std::process;
+
+    /** A path in the temp dir that no other test uses */
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("coffeepot-persist-{}-{}", process::id(), name));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    fn activation(at: DateTime<Utc>, minutes: i64, profile: Option<&str>) -> Activation {
+        Activation {
+            at,
+            brew_time: Duration::minutes(minutes),
+            profile: profile.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn state_file_round_trips() {
+        let path = temp_path("state");
+        let file = StateFile::new(&path);
+        assert_eq!(file.load().unwrap(), None);
+        let at = Utc.ymd(2020, 1, 6).and_hms(6, 15, 0);
+        let saved = SavedState {
+            state: PotState::Waiting,
+            activation: Some(activation(at, 5, Some("mug"))),
+            schedule: vec![
+                ScheduledActivation { id: 3, activation: activation(at + Duration::hours(1), 20, None) },
+                ScheduledActivation { id: 5, activation: activation(at + Duration::days(1), 10, Some("carafe")) },
+            ],
+            armed_at: Some(at - Duration::hours(8)),
+            skipped: vec![(2, at + Duration::days(2))],
+            next_id: 7,
+        };
+        file.save(&saved).unwrap();
+        assert_eq!(file.load().unwrap(), Some(saved));
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn state_file_rejects_brew_times_out_of_range() {
+        let path = temp_path("state-range");
+        let file = StateFile::new(&path);
+        let seconds = i64::MAX;
+        fs::write(&path, format!("state=4\nactivation=2020-01-06T06:15:00+00:00\nbrew_time={}\n", seconds)).unwrap();
+        assert_eq!(file.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::write(&path, format!("state=3\nscheduled=1 2020-01-06T06:15:00+00:00 {}\n", seconds)).unwrap();
+        assert_eq!(file.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rules_file_round_trips() {
+        let path = temp_path("rules");
+        let file = RulesFile::new(&path);
+        assert_eq!(file.load().unwrap(), Vec::new());
+        let rules = vec![
+            "weekdays 06:15 30".parse::<WeeklyRule>().unwrap().with_id(1),
+            "sat,sun 09:00 45".parse::<WeeklyRule>().unwrap().with_id(4),
+        ];
+        file.save(&rules).unwrap();
+        assert_eq!(file.load().unwrap(), rules);
+        fs::write(&path, "1 weekdays 06:15\n").unwrap();
+        assert!(file.load().is_err());
+        fs::remove_file(&path).ok();
+    }
+}
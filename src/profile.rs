@@ -0,0 +1,209 @@
+extern crate chrono;
+use chrono::Duration;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/** Runs an output other than the brewer for a while before brewing */
+#[derive(PartialEq, Clone, Debug)]
+pub struct Stage {
+    pub output: String,
+    pub length: Duration,
+}
+
+/**
+ * A named kind of brew, e.g. a full carafe or a pre-heat cycle. Written as
+ * `<name> <brew minutes> [<keep warm minutes>] [stages=<output>:<seconds>,...]
+ * [pulses=<on>,<off>,...]`, where the name starts with a letter so it can't be
+ * mistaken for a brew time in commands.
+ */
+#[derive(PartialEq, Clone, Debug)]
+pub struct BrewProfile {
+    pub name: String,
+    pub brew_time: Duration,
+    /** Overrides the default keep warm time, zero skips it */
+    pub keep_warm_time: Option<Duration>,
+    /**
+     * Run one after the other from the start of the brew, e.g. grinding the
+     * beans, and count towards the brew time.
+     */
+    pub stages: Vec<Stage>,
+    /**
+     * Alternating relay on and off times from the end of the stages, e.g. to
+     * let the grounds bloom. The relay stays on for the rest of the brew time.
+     */
+    pub pulses: Vec<Duration>,
+}
+
+impl BrewProfile {
+    /** Every output the profile uses besides the brewer */
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|stage| stage.output.as_str())
+    }
+}
+
+/** Minutes that fit in a `chrono::Duration`, which would panic otherwise */
+fn minutes(s: &str) -> Result<Duration, Box<dyn Error>> {
+    let minutes: i64 = s.parse()?;
+    let max = Duration::max_value().num_minutes();
+    if !(-max..=max).contains(&minutes) {
+        return Err(format!("{} minutes is out of range", minutes).into());
+    }
+    Ok(Duration::minutes(minutes))
+}
+
+/** Seconds that are longer than zero and fit in a `chrono::Duration` */
+fn seconds(s: &str) -> Result<Duration, Box<dyn Error>> {
+    let seconds: i64 = s.parse()?;
+    if seconds <= 0 || seconds > Duration::max_value().num_seconds() {
+        return Err(format!("{} seconds is out of range", seconds).into());
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+/** Parses comma separated `<output>:<seconds>`, e.g. `grinder:20` */
+fn parse_stages(s: &str) -> Result<Vec<Stage>, Box<dyn Error>> {
+    s.split(',')
+        .map(|stage| {
+            let mut parts = stage.splitn(2, ':');
+            let output = parts.next().unwrap_or("");
+            let length = seconds(parts.next().ok_or_else(|| format!("stage length missing: {}", stage))?)?;
+            if output.is_empty() {
+                return Err(format!("invalid stage: {}", stage).into());
+            }
+            Ok(Stage {
+                output: output.to_string(),
+                length,
+            })
+        })
+        .collect()
+}
+
+/** Parses comma separated seconds, e.g. `30,30` */
+fn parse_pulses(s: &str) -> Result<Vec<Duration>, Box<dyn Error>> {
+    s.split(',').map(seconds).collect()
+}
+
+impl FromStr for BrewProfile {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let name = parts.next().ok_or("name missing")?;
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return Err(format!("profile name must start with a letter: {}", name).into());
+        }
+        let brew_time = minutes(parts.next().ok_or("brew time missing")?)?;
+        if brew_time <= Duration::zero() {
+            return Err(format!("brew time must be longer than zero: {}", brew_time.num_minutes()).into());
+        }
+        let mut keep_warm_time = None;
+        let mut stages = Vec::new();
+        let mut pulses = Vec::new();
+        for part in parts {
+            if let Some(list) = part.strip_prefix("stages=") {
+                stages = parse_stages(list)?;
+            } else if let Some(seconds) = part.strip_prefix("pulses=") {
+                pulses = parse_pulses(seconds)?;
+            } else if keep_warm_time.is_none() {
+                keep_warm_time = Some(minutes(part)?);
+            } else {
+                return Err(format!("unexpected {}", part).into());
+            }
+        }
+        let staged = stages
+            .iter()
+            .try_fold(Duration::zero(), |total, stage| total.checked_add(&stage.length));
+        if staged.is_none_or(|staged| staged >= brew_time) {
+            return Err("stages take up the whole brew time".into());
+        }
+        Ok(BrewProfile {
+            name: name.to_string(),
+            brew_time,
+            keep_warm_time,
+            stages,
+            pulses,
+        })
+    }
+}
+
+impl fmt::Display for BrewProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.brew_time.num_minutes())?;
+        if let Some(keep_warm_time) = self.keep_warm_time {
+            write!(f, " {}", keep_warm_time.num_minutes())?;
+        }
+        if !self.stages.is_empty() {
+            let stages: Vec<String> = self
+                .stages
+                .iter()
+                .map(|stage| format!("{}:{}", stage.output, stage.length.num_seconds()))
+                .collect();
+            write!(f, " stages={}", stages.join(","))?;
+        }
+        if !self.pulses.is_empty() {
+            let seconds: Vec<String> = self.pulses.iter().map(|p| p.num_seconds().to_string()).collect();
+            write!(f, " pulses={}", seconds.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<BrewProfile, String> {
+        s.parse::<BrewProfile>().map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_and_formats_back() {
+        let profile = parse("carafe 12 30 stages=grinder:20,pump:5 pulses=30,60").unwrap();
+        assert_eq!(profile.name, "carafe");
+        assert_eq!(profile.brew_time, Duration::minutes(12));
+        assert_eq!(profile.keep_warm_time, Some(Duration::minutes(30)));
+        assert_eq!(profile.outputs().collect::<Vec<_>>(), vec!["grinder", "pump"]);
+        assert_eq!(profile.pulses, vec![Duration::seconds(30), Duration::seconds(60)]);
+        assert_eq!(parse(&profile.to_string()), Ok(profile));
+
+        let plain = parse("mug 4").unwrap();
+        assert_eq!(plain.keep_warm_time, None);
+        assert!(plain.stages.is_empty() && plain.pulses.is_empty());
+        assert_eq!(plain.to_string(), "mug 4");
+    }
+
+    #[test]
+    fn malformed_profiles_are_rejected() {
+        assert!(parse("").is_err());
+        assert!(parse("4mug 4").is_err());
+        assert!(parse("mug").is_err());
+        assert!(parse("mug four").is_err());
+        assert!(parse("mug 4 10 20").is_err());
+        assert!(parse("mug 4 stages=grinder").is_err());
+        assert!(parse("mug 4 stages=:20").is_err());
+        assert!(parse("mug 4 stages=grinder:0").is_err());
+        assert!(parse("mug 4 pulses=30,-1").is_err());
+        assert_eq!(parse("mug 1 stages=grinder:60"), Err("stages take up the whole brew time".to_string()));
+    }
+
+    #[test]
+    fn brew_time_must_be_longer_than_zero() {
+        assert_eq!(parse("mug 0"), Err("brew time must be longer than zero: 0".to_string()));
+        assert_eq!(parse("mug -5"), Err("brew time must be longer than zero: -5".to_string()));
+    }
+
+    #[test]
+    fn numbers_out_of_range_are_rejected() {
+        let minutes = (Duration::max_value().num_minutes() + 1).to_string();
+        let seconds = (Duration::max_value().num_seconds() + 1).to_string();
+        assert_eq!(parse(&format!("mug {}", minutes)), Err(format!("{} minutes is out of range", minutes)));
+        assert!(parse(&format!("mug 4 {}", minutes)).is_err());
+        assert!(parse(&format!("mug 4 stages=grinder:{}", seconds)).is_err());
+        assert!(parse(&format!("mug 4 pulses={}", seconds)).is_err());
+        assert!(parse(&format!("mug 4 pulses=30,{}", i64::MAX)).is_err());
+        // each stage fits, but not all of them together
+        let longest = Duration::max_value().num_seconds();
+        assert!(parse(&format!("mug 4 stages=grinder:{},pump:{}", longest, longest)).is_err());
+    }
+}
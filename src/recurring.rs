@@ -0,0 +1,192 @@
+extern crate chrono;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+const DAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/**
+ * Brews at the same local time on a set of weekdays, e.g. weekdays at 06:15.
+ * Written as `<days> <HH:MM> <brew minutes>`, where days is a comma separated
+ * list of `mon`..`sun`, `weekdays`, `weekends` or `daily`.
+ */
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct WeeklyRule {
+    pub id: u32,
+    /** Bit n is set for `Weekday::num_days_from_monday() == n` */
+    days: u8,
+    pub time: NaiveTime,
+    pub brew_time: Duration,
+}
+
+impl WeeklyRule {
+    pub fn with_id(self, id: u32) -> Self {
+        WeeklyRule { id, ..self }
+    }
+
+    pub fn applies_on(&self, day: Weekday) -> bool {
+        self.days & (1 << day.num_days_from_monday()) != 0
+    }
+
+    /** The first activation strictly after `after`, in local time */
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let today = after.with_timezone(&Local).naive_local().date();
+        (0..=7)
+            .map(|offset| today + Duration::days(offset))
+            .filter(|date| self.applies_on(date.weekday()))
+            .filter_map(|date| Local.from_local_datetime(&date.and_time(self.time)).earliest())
+            .map(|t| t.with_timezone(&Utc))
+            .find(|t| *t > after)
+    }
+}
+
+fn parse_days(s: &str) -> Result<u8, Box<dyn Error>> {
+    let mut days = 0;
+    for day in s.split(',') {
+        days |= match day {
+            "daily" => 0b111_1111,
+            "weekdays" => 0b001_1111,
+            "weekends" => 0b110_0000,
+            day => 1 << day.parse::<Weekday>().map_err(|_| format!("invalid day: {}", day))?
+                .num_days_from_monday(),
+        }
+    }
+    Ok(days)
+}
+
+impl FromStr for WeeklyRule {
+    type Err = Box<dyn Error>;
+
+    /** Parses a rule without id, the id is assigned when it is added */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let days = parse_days(parts.next().ok_or("days missing")?)?;
+        let time = NaiveTime::parse_from_str(parts.next().ok_or("time missing")?, "%H:%M")?;
+        let minutes: i64 = parts.next().ok_or("brew time missing")?.parse()?;
+        let max = Duration::max_value().num_minutes();
+        if !(-max..=max).contains(&minutes) {
+            return Err(format!("brew time out of range: {}", minutes).into());
+        }
+        let brew_time = Duration::minutes(minutes);
+        if let Some(extra) = parts.next() {
+            return Err(format!("unexpected {}", extra).into());
+        }
+        Ok(WeeklyRule {
+            id: 0,
+            days,
+            time,
+            brew_time,
+        })
+    }
+}
+
+impl fmt::Display for WeeklyRule {
+    /** Formats the rule without id, so that it can be parsed back */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days: Vec<String> = DAYS
+            .iter()
+            .filter(|d| self.applies_on(**d))
+            .map(|d| format!("{:?}", d).to_lowercase())
+            .collect();
+        write!(
+            f,
+            "{} {} {}",
+            days.join(","),
+            self.time.format("%H:%M"),
+            self.brew_time.num_minutes()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::Once;
+
+    /**
+     * Rules are in local time, so the tests run in Central European Time,
+     * which springs forward on 2020-03-29 and falls back on 2020-10-25.
+     */
+    fn in_cet() {
+        static TZ: Once = Once::new();
+        TZ.call_once(|| env::set_var("TZ", "CET-1CEST,M3.5.0,M10.5.0/3"));
+    }
+
+    fn rule(s: &str) -> WeeklyRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn next_after_is_in_local_time() {
+        in_cet();
+        let rule = rule("mon,wed 06:15 20");
+        let monday = Utc.ymd(2020, 1, 6).and_hms(0, 0, 0);
+        assert_eq!(rule.next_after(monday), Some(Utc.ymd(2020, 1, 6).and_hms(5, 15, 0)));
+        // strictly after
+        let due = Utc.ymd(2020, 1, 6).and_hms(5, 15, 0);
+        assert_eq!(rule.next_after(due), Some(Utc.ymd(2020, 1, 8).and_hms(5, 15, 0)));
+        // summer time
+        let summer = Utc.ymd(2020, 7, 6).and_hms(0, 0, 0);
+        assert_eq!(rule.next_after(summer), Some(Utc.ymd(2020, 7, 6).and_hms(4, 15, 0)));
+    }
+
+    #[test]
+    fn next_after_wraps_across_the_week() {
+        in_cet();
+        let monday = Utc.ymd(2020, 1, 6).and_hms(6, 0, 0);
+        assert_eq!(rule("mon 06:15 20").next_after(monday), Some(Utc.ymd(2020, 1, 13).and_hms(5, 15, 0)));
+        assert_eq!(rule("sun 23:30 20").next_after(monday), Some(Utc.ymd(2020, 1, 12).and_hms(22, 30, 0)));
+        // Sunday 23:30 UTC is already Monday locally
+        let sunday = Utc.ymd(2020, 1, 12).and_hms(23, 30, 0);
+        assert_eq!(rule("sun 06:00 20").next_after(sunday), Some(Utc.ymd(2020, 1, 19).and_hms(5, 0, 0)));
+    }
+
+    #[test]
+    fn next_after_brews_across_daylight_saving_changes() {
+        in_cet();
+        let saturday = Utc.ymd(2020, 3, 28).and_hms(12, 0, 0);
+        // 02:30 doesn't exist on the day clocks go forward, it brews an hour later
+        assert_eq!(rule("sun 02:30 20").next_after(saturday), Some(Utc.ymd(2020, 3, 29).and_hms(1, 30, 0)));
+        assert_eq!(rule("sun 06:15 20").next_after(saturday), Some(Utc.ymd(2020, 3, 29).and_hms(4, 15, 0)));
+        let saturday = Utc.ymd(2020, 10, 24).and_hms(12, 0, 0);
+        assert_eq!(rule("sun 06:15 20").next_after(saturday), Some(Utc.ymd(2020, 10, 25).and_hms(5, 15, 0)));
+    }
+
+    #[test]
+    fn days_are_parsed_and_formatted_back() {
+        assert_eq!(parse_days("mon").unwrap(), 0b000_0001);
+        assert_eq!(parse_days("sun").unwrap(), 0b100_0000);
+        assert_eq!(parse_days("mon,wed,fri").unwrap(), 0b001_0101);
+        assert_eq!(parse_days("weekdays").unwrap(), 0b001_1111);
+        assert_eq!(parse_days("weekends,mon").unwrap(), 0b110_0001);
+        assert_eq!(parse_days("daily").unwrap(), 0b111_1111);
+        assert!(parse_days("").is_err());
+        assert!(parse_days("mon,").is_err());
+        assert!(parse_days("someday").is_err());
+        assert_eq!(rule("weekdays 06:15 30").to_string(), "mon,tue,wed,thu,fri 06:15 30");
+        assert_eq!(rule("weekends,daily 9:05 5").to_string(), "mon,tue,wed,thu,fri,sat,sun 09:05 5");
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        assert!("".parse::<WeeklyRule>().is_err());
+        assert!("mon".parse::<WeeklyRule>().is_err());
+        assert!("mon 06:00".parse::<WeeklyRule>().is_err());
+        assert!("mon 6am 20".parse::<WeeklyRule>().is_err());
+        assert!("mon 06:00 twenty".parse::<WeeklyRule>().is_err());
+        assert!("mon 06:00 20 junk".parse::<WeeklyRule>().is_err());
+        assert!("mon 06:00 20 07:00 30".parse::<WeeklyRule>().is_err());
+        assert!(format!("mon 06:00 {}", i64::MAX).parse::<WeeklyRule>().is_err());
+    }
+}
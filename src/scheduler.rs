@@ -0,0 +1,177 @@
+extern crate chrono;
+extern crate timer;
+use chrono::{DateTime, Duration, Utc};
+#[cfg(test)]
+use std::sync::{Arc, Mutex, Weak};
+
+/**
+ * Source of time and delayed callbacks for the coffeepot state machine.
+ * Dropping the returned guard cancels the scheduled callback.
+ */
+pub trait Scheduler: Send + 'static {
+    type Guard: Send + 'static;
+
+    fn now(&self) -> DateTime<Utc>;
+
+    fn schedule_with_date<F: FnMut() + Send + 'static>(
+        &self,
+        date: DateTime<Utc>,
+        cb: F,
+    ) -> Self::Guard;
+
+    fn schedule_with_delay<F: FnMut() + Send + 'static>(
+        &self,
+        delay: Duration,
+        cb: F,
+    ) -> Self::Guard {
+        self.schedule_with_date(self.now() + delay, cb)
+    }
+}
+
+/** Production scheduler, backed by wall time */
+impl Scheduler for timer::Timer {
+    type Guard = timer::Guard;
+
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn schedule_with_date<F: FnMut() + Send + 'static>(
+        &self,
+        date: DateTime<Utc>,
+        cb: F,
+    ) -> Self::Guard {
+        timer::Timer::schedule_with_date(self, date, cb)
+    }
+
+    fn schedule_with_delay<F: FnMut() + Send + 'static>(
+        &self,
+        delay: Duration,
+        cb: F,
+    ) -> Self::Guard {
+        timer::Timer::schedule_with_delay(self, delay, cb)
+    }
+}
+
+#[cfg(test)]
+struct VirtualEntry {
+    id: u64,
+    date: DateTime<Utc>,
+    cb: Box<dyn FnMut() + Send>,
+}
+
+#[cfg(test)]
+struct VirtualInternals {
+    now: DateTime<Utc>,
+    next_id: u64,
+    entries: Vec<VirtualEntry>,
+}
+
+/**
+ * Scheduler where time only moves when `advance` is called, so that timed
+ * transitions can be stepped through deterministically.
+ */
+#[cfg(test)]
+#[derive(Clone)]
+pub struct VirtualScheduler {
+    props: Arc<Mutex<VirtualInternals>>,
+}
+
+#[cfg(test)]
+pub struct VirtualGuard {
+    id: u64,
+    props: Weak<Mutex<VirtualInternals>>,
+}
+
+#[cfg(test)]
+impl Drop for VirtualGuard {
+    fn drop(&mut self) {
+        if let Some(props) = self.props.upgrade() {
+            props.lock().unwrap().entries.retain(|e| e.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+impl VirtualScheduler {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        VirtualScheduler {
+            props: Arc::new(Mutex::new(VirtualInternals {
+                now: start,
+                next_id: 0,
+                entries: Vec::new(),
+            })),
+        }
+    }
+
+    /**
+     * Moves time forward, running every callback that falls due on the way in
+     * order, with the time set to when it was due. Callbacks are run without
+     * holding the scheduler lock, so they may schedule further callbacks,
+     * which also run if due before the new time. Time only moves on once a
+     * callback returns, which for the coffeepot's timers is once the event
+     * loop has handled them.
+     */
+    pub fn advance(&self, by: Duration) {
+        let target = self.now() + by;
+        loop {
+            let next = {
+                let mut attrs = self.props.lock().unwrap();
+                let due = attrs
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, e)| e.date <= target)
+                    .min_by_key(|(_, e)| (e.date, e.id))
+                    .map(|(i, _)| i);
+                due.map(|i| {
+                    let entry = attrs.entries.remove(i);
+                    if entry.date > attrs.now {
+                        attrs.now = entry.date;
+                    }
+                    entry
+                })
+            };
+            match next {
+                Some(mut entry) => {
+                    debug!("virtual scheduler firing entry {}", entry.id);
+                    (entry.cb)();
+                }
+                None => break,
+            }
+        }
+        self.props.lock().unwrap().now = target;
+    }
+
+    pub fn pending(&self) -> usize {
+        self.props.lock().unwrap().entries.len()
+    }
+}
+
+#[cfg(test)]
+impl Scheduler for VirtualScheduler {
+    type Guard = VirtualGuard;
+
+    fn now(&self) -> DateTime<Utc> {
+        self.props.lock().unwrap().now
+    }
+
+    fn schedule_with_date<F: FnMut() + Send + 'static>(
+        &self,
+        date: DateTime<Utc>,
+        cb: F,
+    ) -> Self::Guard {
+        let mut attrs = self.props.lock().unwrap();
+        let id = attrs.next_id;
+        attrs.next_id += 1;
+        attrs.entries.push(VirtualEntry {
+            id,
+            date,
+            cb: Box::new(cb),
+        });
+        VirtualGuard {
+            id,
+            props: Arc::downgrade(&self.props),
+        }
+    }
+}
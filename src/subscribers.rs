@@ -0,0 +1,87 @@
+use crate::coffeepot::{Cause, Transition};
+use crate::mqtt::Mqtt;
+use std::time::{Duration, Instant};
+
+/**
+ * Publishes `{"stage": <stage>, "event": <event>, "at": <time>}` to
+ * `coffeepot/stage`, where event is `start`, `end`, or `aborted` when the brew
+ * stopped or started over some other way than running out
+ */
+fn publish_stage(mqtt: &mut Mqtt, stage: &str, event: &str, transition: &Transition) {
+    let json = serde_json::json!({ "stage": stage, "event": event, "at": transition.at });
+    mqtt.publish("stage", json.to_string());
+}
+
+/**
+ * Publishes the new state to `coffeepot/state` and the whole transition as
+ * JSON to `coffeepot/transition`, both retained, events to `coffeepot/event`
+ * and the start and end of every stage to `coffeepot/stage`. Stages and
+ * pulses don't change the state, so a brew is published as a single Active
+ * phase.
+ */
+pub fn mqtt_publisher(mut mqtt: Mqtt) -> impl FnMut(Transition) + Send {
+    let mut stage: Option<String> = None;
+    move |transition| {
+        if transition.stage != stage || transition.is_restart() {
+            if let Some(ended) = stage.take() {
+                let ran_out = matches!(transition.cause, Cause::Stage | Cause::Timeout);
+                publish_stage(&mut mqtt, &ended, if ran_out { "end" } else { "aborted" }, &transition);
+            }
+            if let Some(started) = &transition.stage {
+                publish_stage(&mut mqtt, started, "start", &transition);
+            }
+            stage = transition.stage.clone();
+        }
+        if transition.is_step() {
+            return;
+        }
+        mqtt.publish_retained("state", vec![transition.to as u8]);
+        match serde_json::to_string(&transition) {
+            Ok(json) => {
+                mqtt.publish_retained("transition", json);
+            }
+            Err(e) => error!("could not serialize transition: {}", e),
+        }
+        if transition.is_ready_expiry() {
+            crate::publish_event(&mut mqtt, "armed-expired");
+        }
+    }
+}
+
+pub fn logger(transition: Transition) {
+    if transition.is_step() {
+        info!("stage {:?}, outputs on: {:?}", transition.stage, transition.outputs);
+        return;
+    }
+    match &transition.origin {
+        Some(origin) => info!(
+            "state changed from {:?} to {:?} by {:?} ({})",
+            transition.from, transition.to, transition.cause, origin
+        ),
+        None => info!(
+            "state changed from {:?} to {:?} by {:?}",
+            transition.from, transition.to, transition.cause
+        ),
+    }
+}
+
+/** Counts brews and the total time the relay has been on */
+pub fn stats() -> impl FnMut(Transition) + Send {
+    let mut brews = 0;
+    let mut relay_on_since: Option<Instant> = None;
+    let mut relay_on_total = Duration::from_secs(0);
+    move |transition| {
+        match (relay_on_since, transition.to.powers_relay()) {
+            (None, true) => {
+                brews += 1;
+                relay_on_since = Some(Instant::now());
+            }
+            (Some(since), false) => {
+                relay_on_total += since.elapsed();
+                relay_on_since = None;
+                info!("{} brews, relay on for {:?} in total", brews, relay_on_total);
+            }
+            _ => (),
+        }
+    }
+}
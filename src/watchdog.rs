@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/**
+ * Trips if the relay stays on for longer than an absolute maximum, no matter
+ * what the state machine thinks. Runs on its own thread with its own clock,
+ * so that it keeps working even if the coffeepot timer thread or mutex dies.
+ * Once tripped it stays latched until `reset` is called.
+ */
+pub struct Watchdog {
+    tx: Sender<bool>,
+    tripped: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn new<F: FnMut() + Send + 'static>(max_on_time: Duration, mut on_trip: F) -> Self {
+        let (tx, rx) = channel();
+        let tripped = Arc::new(AtomicBool::new(false));
+        thread::spawn({
+            let tripped = tripped.clone();
+            move || {
+                let mut on_since: Option<Instant> = None;
+                loop {
+                    let message = match on_since {
+                        Some(since) => rx.recv_timeout(
+                            max_on_time.checked_sub(since.elapsed()).unwrap_or_default(),
+                        ),
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match message {
+                        // staying on doesn't restart the clock
+                        Ok(true) => on_since = on_since.or_else(|| Some(Instant::now())),
+                        Ok(false) => on_since = None,
+                        Err(RecvTimeoutError::Timeout) => {
+                            error!("relay on for more than {:?}, tripping watchdog", max_on_time);
+                            tripped.store(true, Ordering::SeqCst);
+                            on_since = None;
+                            on_trip();
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            info!("exiting watchdog loop");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Watchdog { tx, tripped }
+    }
+
+    /** Must be called every time the relay is switched */
+    pub fn relay_changed(&self, on: bool) {
+        self.tx
+            .send(on)
+            .map_err(|_| error!("watchdog thread died"))
+            .ok();
+    }
+
+    /** While tripped the relay must be kept off */
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+
+    pub fn reset(&self) {
+        info!("resetting watchdog");
+        self.tripped.store(false, Ordering::SeqCst);
+    }
+}